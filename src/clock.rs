@@ -0,0 +1,58 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+pub const TICKS_PER_SECOND: u32 = 20;
+
+/* how many recent ticks are used to measure the tick rate */
+const TPS_WINDOW: usize = 5 * TICKS_PER_SECOND as usize;
+
+/// Schedules ticks at a fixed rate and measures how many actually happen.
+pub struct TickClock {
+    tick_length: Duration,
+    next_tick: Instant,
+    recent: VecDeque<Instant>,
+}
+
+impl TickClock {
+    pub fn new(now: Instant) -> TickClock {
+        let tick_length = Duration::from_secs(1) / TICKS_PER_SECOND;
+        TickClock {
+            tick_length,
+            next_tick: now + tick_length,
+            recent: VecDeque::with_capacity(TPS_WINDOW + 1),
+        }
+    }
+    /// How long to wait before the next tick is due.
+    pub fn until_next_tick(&self, now: Instant) -> Duration {
+        self.next_tick.saturating_duration_since(now)
+    }
+    /// Starts a tick if one is due. When the loop is running so late that
+    /// more than a whole tick was missed, the missed ticks are dropped rather
+    /// than run back to back.
+    pub fn start_tick(&mut self, now: Instant) -> bool {
+        if now < self.next_tick {
+            return false;
+        }
+        let late = now - self.next_tick;
+        if late >= self.tick_length {
+            println!("Tick overrun: running {} ms behind, skipping {} ticks",
+                late.as_millis(), late.as_nanos() / self.tick_length.as_nanos());
+            self.next_tick = now + self.tick_length;
+        } else {
+            self.next_tick += self.tick_length;
+        }
+        self.recent.push_back(now);
+        if self.recent.len() > TPS_WINDOW {
+            self.recent.pop_front();
+        }
+        true
+    }
+    /// Ticks per second, measured over the last few seconds.
+    pub fn tps(&self) -> f64 {
+        match (self.recent.front(), self.recent.back()) {
+            (Some(first), Some(last)) if first < last =>
+                (self.recent.len() - 1) as f64 / (*last - *first).as_secs_f64(),
+            _ => 0.0,
+        }
+    }
+}
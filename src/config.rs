@@ -0,0 +1,272 @@
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::convert::Infallible;
+use std::str::FromStr;
+
+/// Read when no `--config` is given, if it exists.
+const DEFAULT_CONFIG_FILE: &str = "server.toml";
+
+const USAGE: &str = "\
+Usage: server [options]
+
+Options:
+    --config FILE         read settings from FILE (default: server.toml, if present)
+    --bind ADDR           address to listen on, e.g. 0.0.0.0:4080
+    --world DIR           directory the world is saved in
+    --storage KIND        how the world is stored: region or sqlite
+    --generator KIND      terrain of a new world: noise, superflat or void
+    --seed SEED           seed for a new world: a number, or text to hash
+    --day-length SECS     length of a day in seconds
+    --autosave SECS       time between autosaves in seconds
+    --view-distance N     chunks around each player kept loaded
+    --nick-pattern P      default nickname; {id} is replaced by the client id
+    -h, --help            show this message";
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    /// Chunks grouped in region files inside the world directory.
+    Region,
+    /// A single SQLite database inside the world directory.
+    Sqlite,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<StorageKind, String> {
+        match s {
+            "region" => Ok(StorageKind::Region),
+            "sqlite" => Ok(StorageKind::Sqlite),
+            _ => Err("expected region or sqlite".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GeneratorKind {
+    /// Seas, plains and mountains.
+    Noise,
+    /// Flat layers of blocks, as given by `superflat_layers`.
+    Superflat,
+    /// Nothing at all.
+    Void,
+}
+
+impl GeneratorKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            GeneratorKind::Noise => "noise",
+            GeneratorKind::Superflat => "superflat",
+            GeneratorKind::Void => "void",
+        }
+    }
+}
+
+impl FromStr for GeneratorKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<GeneratorKind, String> {
+        match s {
+            "noise" => Ok(GeneratorKind::Noise),
+            "superflat" => Ok(GeneratorKind::Superflat),
+            "void" => Ok(GeneratorKind::Void),
+            _ => Err("expected noise, superflat or void".to_string()),
+        }
+    }
+}
+
+/// What the world generator of a new world is seeded with.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum Seed {
+    Number(u32),
+    /// Hashed into a number, so that worlds can be named rather than numbered.
+    Text(String),
+}
+
+impl Seed {
+    pub fn value(&self) -> u32 {
+        match self {
+            Seed::Number(n) => *n,
+            Seed::Text(s) => crc32fast::hash(s.as_bytes()),
+        }
+    }
+}
+
+impl FromStr for Seed {
+    type Err = Infallible;
+    fn from_str(s: &str) -> Result<Seed, Infallible> {
+        Ok(s.parse().map(Seed::Number).unwrap_or_else(|_| Seed::Text(s.to_string())))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: SocketAddr,
+    pub world: PathBuf,
+    pub storage: StorageKind,
+    /// Seed of a new world; random if not given. A world keeps the seed it
+    /// was created with, and a different one here is ignored.
+    pub seed: Option<Seed>,
+    /// Only used when creating the world, as are the settings below.
+    pub generator: GeneratorKind,
+    /// Block ids of the layers of a superflat world, from the bottom up.
+    pub superflat_layers: Vec<u8>,
+    /// Day length in seconds.
+    pub day_length: usize,
+    /// Seconds between autosaves.
+    pub autosave_interval: usize,
+    /// How far from a player, in chunks, chunks are kept loaded.
+    pub view_distance: i64,
+    /// Most chunks kept loaded; the least recently used go first.
+    pub max_loaded_chunks: usize,
+    /// Threads loading and generating chunks.
+    pub loader_threads: usize,
+    /// Most chunks waiting to be loaded; past that, requests for the
+    /// farthest are refused.
+    pub loader_queue: usize,
+    pub nick_pattern: String,
+    /// Largest frame a client may send before being kicked.
+    pub max_frame_size: usize,
+    /// Most unsent data a client may have before being dropped.
+    pub max_backlog: usize,
+    /// Clients connecting from these addresses may use admin commands.
+    pub admins: Vec<IpAddr>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 4080),
+            world: PathBuf::from("world"),
+            storage: StorageKind::Region,
+            seed: None,
+            generator: GeneratorKind::Noise,
+            superflat_layers: vec![1, 1, 1, 3],
+            day_length: 600,
+            autosave_interval: 300,
+            view_distance: 10,
+            max_loaded_chunks: 4096,
+            loader_threads: 2,
+            loader_queue: 1024,
+            nick_pattern: "person{id}".to_string(),
+            max_frame_size: 64 * 1024,
+            max_backlog: 4 * 1024 * 1024,
+            admins: vec![IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::LOCALHOST)],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// `--help` was given; not really an error, but startup stops there.
+    Help,
+    Usage(String),
+    Read(PathBuf, String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Help => write!(f, "{}", USAGE),
+            ConfigError::Usage(msg) => write!(f, "{}\n\n{}", msg, USAGE),
+            ConfigError::Read(path, msg) => write!(f, "{}: {}", path.display(), msg),
+            ConfigError::Invalid(msg) => write!(f, "invalid configuration: {}", msg),
+        }
+    }
+}
+
+fn parse_arg<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, ConfigError> where T::Err: fmt::Display {
+    let value = value.ok_or_else(|| ConfigError::Usage(format!("{} needs a value", flag)))?;
+    value.parse().map_err(|e| ConfigError::Usage(format!("invalid value {:?} for {}: {}", value, flag, e)))
+}
+
+impl Config {
+    /// Builds the configuration from the config file, then the command line.
+    pub fn from_args<I: Iterator<Item = String>>(args: I) -> Result<Config, ConfigError> {
+        let args: Vec<String> = args.collect();
+        let mut config = match args.iter().position(|a| a == "--config") {
+            Some(i) => match args.get(i + 1) {
+                Some(path) => Config::load(PathBuf::from(path))?,
+                None => return Err(ConfigError::Usage("--config needs a value".to_string())),
+            },
+            None if fs::metadata(DEFAULT_CONFIG_FILE).is_ok() => Config::load(PathBuf::from(DEFAULT_CONFIG_FILE))?,
+            None => Config::default(),
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Err(ConfigError::Help),
+                "--config" => { args.next(); }
+                "--bind" => config.bind = parse_arg(&arg, args.next())?,
+                "--world" => config.world = parse_arg(&arg, args.next())?,
+                "--storage" => config.storage = parse_arg(&arg, args.next())?,
+                "--generator" => config.generator = parse_arg(&arg, args.next())?,
+                "--seed" => config.seed = Some(parse_arg(&arg, args.next())?),
+                "--day-length" => config.day_length = parse_arg(&arg, args.next())?,
+                "--autosave" => config.autosave_interval = parse_arg(&arg, args.next())?,
+                "--view-distance" => config.view_distance = parse_arg(&arg, args.next())?,
+                "--nick-pattern" => config.nick_pattern = parse_arg(&arg, args.next())?,
+                _ => return Err(ConfigError::Usage(format!("unknown option {}", arg))),
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+    pub fn from_env() -> Result<Config, ConfigError> {
+        Config::from_args(env::args().skip(1))
+    }
+    fn load(path: PathBuf) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(&path).map_err(|e| ConfigError::Read(path.clone(), e.to_string()))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Read(path, e.to_string()))
+    }
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.day_length == 0 {
+            return Err(ConfigError::Invalid("day_length must be at least 1 second".to_string()));
+        }
+        if self.autosave_interval == 0 {
+            return Err(ConfigError::Invalid("autosave_interval must be at least 1 second".to_string()));
+        }
+        if self.view_distance < 1 {
+            return Err(ConfigError::Invalid("view_distance must be at least 1 chunk".to_string()));
+        }
+        if self.max_loaded_chunks == 0 {
+            return Err(ConfigError::Invalid("max_loaded_chunks must be at least 1".to_string()));
+        }
+        if self.loader_threads == 0 {
+            return Err(ConfigError::Invalid("loader_threads must be at least 1".to_string()));
+        }
+        if self.loader_queue == 0 {
+            return Err(ConfigError::Invalid("loader_queue must be at least 1".to_string()));
+        }
+        if !self.nick_pattern.contains("{id}") {
+            return Err(ConfigError::Invalid("nick_pattern must contain {id} so nicknames are unique".to_string()));
+        }
+        if self.max_frame_size < 1024 {
+            return Err(ConfigError::Invalid("max_frame_size must be at least 1024 bytes".to_string()));
+        }
+        /* a single uncompressible chunk must fit */
+        if self.max_backlog < 64 * 1024 {
+            return Err(ConfigError::Invalid("max_backlog must be at least 65536 bytes".to_string()));
+        }
+        Ok(())
+    }
+    /// Settings of the generator of a new world, to be saved with it.
+    pub fn generator_settings(&self) -> toml::value::Table {
+        let mut settings = toml::value::Table::new();
+        if self.generator == GeneratorKind::Superflat {
+            let layers = self.superflat_layers.iter().map(|&id| toml::Value::Integer(id as i64)).collect();
+            settings.insert("layers".to_string(), toml::Value::Array(layers));
+        }
+        settings
+    }
+    pub fn nick(&self, id: usize) -> String {
+        self.nick_pattern.replace("{id}", &id.to_string())
+    }
+}
@@ -0,0 +1,149 @@
+use byteorder::{ByteOrder, NetworkEndian};
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+
+enum ReadState {
+    Length([u8; 4], usize),
+    Body(Vec<u8>, usize),
+}
+
+/// Reassembles length-prefixed frames from a non-blocking stream, keeping
+/// whatever was read so far across calls.
+pub struct FrameReader {
+    state: ReadState,
+    max_frame: usize,
+}
+
+/* reads as much as is available into buf; None means the read would block */
+fn read_some<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<Option<usize>> {
+    loop {
+        match r.read(buf) {
+            Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed")),
+            Ok(n) => return Ok(Some(n)),
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+impl FrameReader {
+    pub fn new(max_frame: usize) -> FrameReader {
+        FrameReader {
+            state: ReadState::Length([0; 4], 0),
+            max_frame,
+        }
+    }
+    /// Returns the next complete frame, or `None` if the stream has no more
+    /// data for now. Frames longer than the maximum are an `InvalidData` error.
+    pub fn read_frame<R: Read>(&mut self, r: &mut R) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.state {
+                ReadState::Length(ref len, 4) => {
+                    let size = NetworkEndian::read_u32(len) as usize;
+                    if size > self.max_frame {
+                        return Err(io::Error::new(ErrorKind::InvalidData,
+                            format!("frame of {} bytes exceeds the {} byte limit", size, self.max_frame)));
+                    }
+                    self.state = ReadState::Body(vec![0; size], 0);
+                }
+                ReadState::Length(ref mut len, ref mut read) => match read_some(r, &mut len[*read..])? {
+                    Some(n) => *read += n,
+                    None => return Ok(None),
+                }
+                ReadState::Body(ref mut buf, read) if read == buf.len() => {
+                    let frame = mem::take(buf);
+                    self.state = ReadState::Length([0; 4], 0);
+                    return Ok(Some(frame));
+                }
+                ReadState::Body(ref mut buf, ref mut read) => match read_some(r, &mut buf[*read..])? {
+                    Some(n) => *read += n,
+                    None => return Ok(None),
+                }
+            }
+        }
+    }
+}
+
+/// Buffers whatever the socket does not accept right away, so that writes
+/// never block and never lose data. Once the backlog goes over its cap, or the
+/// stream fails, the queue is marked as failed and refuses further writes.
+pub struct Queue<T> {
+    stream: T,
+    wqueue: Vec<u8>,
+    written: usize,
+    max_backlog: usize,
+    failure: Option<String>,
+}
+
+impl<T> Queue<T> {
+    pub fn new(stream: T, max_backlog: usize) -> Queue<T> {
+        Queue {
+            stream,
+            wqueue: Vec::new(),
+            written: 0,
+            max_backlog,
+            failure: None,
+        }
+    }
+    pub fn get_ref(&self) -> &T {
+        &self.stream
+    }
+    pub fn pending(&self) -> usize {
+        self.wqueue.len() - self.written
+    }
+    pub fn failure(&self) -> Option<&str> {
+        self.failure.as_deref()
+    }
+    fn fail(&mut self, err: io::Error) -> io::Error {
+        self.failure = Some(err.to_string());
+        err
+    }
+}
+
+impl<T: Write> Queue<T> {
+    /// Writes as much of the backlog as the stream accepts without blocking.
+    pub fn send_pending(&mut self) -> io::Result<()> {
+        while self.written < self.wqueue.len() {
+            match self.stream.write(&self.wqueue[self.written..]) {
+                Ok(0) => return Err(self.fail(io::Error::new(ErrorKind::WriteZero, "connection closed"))),
+                Ok(n) => self.written += n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(self.fail(e)),
+            }
+        }
+        if self.written == self.wqueue.len() {
+            self.wqueue.clear();
+            self.written = 0;
+        } else if self.written > self.wqueue.len() / 2 {
+            self.wqueue.drain(..self.written);
+            self.written = 0;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Write> Write for Queue<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(ref f) = self.failure {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, f.clone()));
+        }
+        if self.pending() + buf.len() > self.max_backlog {
+            let msg = format!("outbound backlog exceeds {} bytes", self.max_backlog);
+            return Err(self.fail(io::Error::other(msg)));
+        }
+        self.wqueue.extend_from_slice(buf);
+        self.send_pending()?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_pending()
+    }
+}
+
+impl<T: Read> Read for Queue<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
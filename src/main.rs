@@ -1,57 +1,126 @@
+mod clock;
+mod config;
+mod connection;
+mod protocol;
 mod world;
-use byteorder::{ReadBytesExt, NetworkEndian};
-use mio::net::TcpListener;
+use config::{Config, ConfigError};
+use connection::{FrameReader, Queue};
+use mio::net::{TcpListener, TcpStream};
 use mio::{Token, Poll, PollOpt, Ready, Events};
 use world::Server;
 use std::collections::HashMap;
-use std::io::{self, ErrorKind, Read, Write};
-use std::time::Instant;
+use std::io::{self, ErrorKind, Write};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-struct Queue<T> {
-    stream: T,
-    wqueue: Vec<u8>,
+struct Client {
+    queue: Arc<RwLock<Queue<TcpStream>>>,
+    reader: FrameReader,
+    writable: bool,
 }
 
-impl<T> Queue<T> {
-    fn new(stream: T) -> Queue<T> {
-        Queue {
-            stream: stream,
-            wqueue: Vec::new(),
-        }
+fn interest(writable: bool) -> Ready {
+    if writable {
+        Ready::readable() | Ready::writable()
+    } else {
+        Ready::readable()
     }
 }
 
-impl<T: Write> Write for Queue<T> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.wqueue.extend_from_slice(buf);
-        match self.stream.write(&self.wqueue) {
-            Ok(n) => {
-                self.wqueue.clear();
-                Ok(n)
-            }
-            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
-                /* do nothing, flush will be called later */
-                Ok(buf.len())
-            }
-            Err(err) => Err(err),
-        }
+fn report_read_error(id: usize, e: &io::Error) {
+    match e.kind() {
+        ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset => println!("Client {} disconnected", id),
+        _ => println!("Client {}: read error: {}", id, e),
+    }
+}
+
+fn drop_client(server: &mut Server<Queue<TcpStream>>, clients: &mut HashMap<usize, Client>, id: usize) {
+    clients.remove(&id);
+    if let Err(e) = server.disconnect(id) {
+        println!("Error while disconnecting client {}: {}", id, e);
     }
-    fn flush(&mut self) -> io::Result<()> {
-        self.stream.write(&self.wqueue).map(|_| {})
+}
+
+/* only ask for writable events while there is something to write;
+ * returns the clients whose queue has failed */
+fn update_interest(poll: &Poll, clients: &mut HashMap<usize, Client>) -> Vec<(usize, String)> {
+    let mut failed = Vec::new();
+    for (id, client) in clients.iter_mut() {
+        let queue = client.queue.read().unwrap();
+        if let Some(reason) = queue.failure() {
+            failed.push((*id, reason.to_string()));
+            continue;
+        }
+        let writable = queue.pending() > 0;
+        if writable != client.writable {
+            poll.reregister(queue.get_ref(), Token(*id), interest(writable), PollOpt::edge()).unwrap();
+            client.writable = writable;
+        }
     }
+    failed
 }
 
-impl<T: Read> Read for Queue<T> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.stream.read(buf)
+/// How long to keep trying to deliver pending data when shutting down.
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn flush_all(poll: &Poll, events: &mut Events, clients: &mut HashMap<usize, Client>) {
+    let deadline = Instant::now() + SHUTDOWN_FLUSH_TIMEOUT;
+    loop {
+        for client in clients.values() {
+            let _ = client.queue.write().unwrap().send_pending();
+        }
+        for (id, _) in update_interest(poll, clients) {
+            clients.remove(&id);
+        }
+        if clients.values().all(|c| !c.writable) {
+            break;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            println!("Gave up flushing {} clients", clients.values().filter(|c| c.writable).count());
+            break;
+        }
+        match poll.poll(events, Some(deadline - now)) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => panic!("Error: {:?}", e),
+        }
     }
 }
 
 fn main() {
     const SERVER: Token = Token(0);
 
-    let listener = TcpListener::bind(&"0.0.0.0:4080".parse().unwrap()).unwrap();
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(ConfigError::Help) => {
+            println!("{}", ConfigError::Help);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+
+    let mut server = match Server::new(&config) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Could not open world {}: {}", config.world.display(), e);
+            process::exit(1);
+        }
+    };
+
+    let listener = match TcpListener::bind(&config.bind) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Could not listen on {}: {}", config.bind, e);
+            process::exit(1);
+        }
+    };
+    println!("Listening on {}", config.bind);
     let poll = Poll::new().unwrap();
     poll.register(&listener, SERVER, Ready::readable() | Ready::writable(), PollOpt::edge()).unwrap();
     let mut events = Events::with_capacity(1024);
@@ -59,81 +128,110 @@ fn main() {
     let mut new_id = 1;
 
     let mut clients = HashMap::new();
-    let mut server = Server::new();
 
-    let mut now = Instant::now();
+    let terminate = Arc::new(AtomicBool::new(false));
+    for &signal in &[signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register(signal, Arc::clone(&terminate)).unwrap();
+    }
 
-    loop {
-        poll.poll(&mut events, None).unwrap();
+    while !server.is_stopping() {
+        match poll.poll(&mut events, Some(server.time_to_next_tick())) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => events.clear(),
+            Err(e) => panic!("Error: {:?}", e),
+        }
+        if terminate.load(Ordering::Relaxed) {
+            server.stop("received a termination signal");
+        }
 
         for event in events.iter() {
-            server.tick(now.elapsed().as_secs() as f32 + now.elapsed().subsec_millis() as f32 * 1e-3);
-            now = Instant::now();
             match event.token() {
                 SERVER => {
                     loop {
                         match listener.accept() {
-                            Ok((stream, _)) => {
+                            Ok((stream, addr)) => {
+                                /* there are no accounts, so admins are recognized by address */
+                                let admin = config.admins.contains(&addr.ip());
                                 poll.register(&stream, Token(new_id), Ready::readable(), PollOpt::edge()).unwrap();
-                                let s = Arc::new(RwLock::new(Queue::new(stream)));
-                                clients.insert(new_id, Arc::clone(&s));
-                                server.connect(s, new_id).unwrap();
+                                let s = Arc::new(RwLock::new(Queue::new(stream, config.max_backlog)));
+                                clients.insert(new_id, Client {
+                                    queue: Arc::clone(&s),
+                                    reader: FrameReader::new(config.max_frame_size),
+                                    writable: false,
+                                });
+                                if let Err(e) = server.connect(s, new_id, admin) {
+                                    println!("Error while connecting client {}: {}", new_id, e);
+                                }
                                 println!("New client: {}", new_id);
                                 new_id += 1;
                             }
                             Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
                                 break;
                             }
-                            e => panic!("Error: {:?}", e),
+                            Err(e) => {
+                                /* most likely out of file descriptors; try again on the next event */
+                                println!("Error while accepting a client: {}", e);
+                                break;
+                            }
                         }
                     }
                 }
                 Token(id) => {
+                    let client = match clients.get(&id) {
+                        Some(c) => c,
+                        None => continue,
+                    };
                     if event.readiness().is_writable() {
-                        clients.get_mut(&id).unwrap().write().unwrap().flush().unwrap();
+                        let flushed = client.queue.write().unwrap().flush();
+                        if let Err(e) = flushed {
+                            println!("Client {}: write error: {}", id, e);
+                            drop_client(&mut server, &mut clients, id);
+                            continue;
+                        }
                     }
                     if event.readiness().is_readable() {
                         loop {
-                            let mut reader = clients.get_mut(&id).unwrap().write().unwrap();
-                            let size = match reader.read_u32::<NetworkEndian>() {
-                                Ok(n) => n as usize,
-                                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof
-                                           || e.kind() == ErrorKind::ConnectionReset => {
-                                    drop(reader);
-                                    server.disconnect(id).unwrap();
-                                    clients.remove(&id);
-                                    println!("Client {} disconnected", id);
-                                    break;
+                            let client = clients.get_mut(&id).unwrap();
+                            let frame = client.reader.read_frame(&mut *client.queue.write().unwrap());
+                            let reason = match frame {
+                                Ok(Some(buf)) => match server.process_message(id, &buf) {
+                                    Ok(()) => continue,
+                                    Err(e) => e.to_string(),
                                 }
-                                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                                Ok(None) => break,
+                                Err(ref e) if e.kind() == ErrorKind::InvalidData => e.to_string(),
+                                Err(e) => {
+                                    report_read_error(id, &e);
+                                    drop_client(&mut server, &mut clients, id);
                                     break;
                                 }
-                                e => panic!("Error: {:?}", e),
                             };
-                            let mut buf = vec![0; size];
-                            let mut read = 0;
-                            loop {
-                                match reader.read(&mut buf[read..]) {
-                                    Ok(n) => {
-                                        read += n;
-                                        if read == size {
-                                            drop(reader);
-                                            server.process_message(id, &buf).unwrap();
-                                            break;
-                                        }
-                                    }
-                                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                                        /* XXX */
-                                        continue;
-                                    }
-                                    e => panic!("Error: {:?}", e),
-                                }
+                            if let Err(e) = server.kick(id, &reason) {
+                                println!("Error while kicking client {}: {}", id, e);
+                            }
+                            if let Some(client) = clients.remove(&id) {
+                                let _ = client.queue.write().unwrap().flush();
                             }
+                            break;
                         }
                     }
                 }
             }
         }
+
+        server.update();
+
+        for (id, reason) in update_interest(&poll, &mut clients) {
+            println!("Client {}: {}", id, reason);
+            drop_client(&mut server, &mut clients, id);
+        }
+    }
+
+    drop(listener);
+    if let Err(e) = server.shutdown() {
+        println!("Error while shutting down: {}", e);
     }
+    flush_all(&poll, &mut events, &mut clients);
+    println!("Server stopped");
 }
 
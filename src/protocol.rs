@@ -0,0 +1,265 @@
+use byteorder::{WriteBytesExt, NetworkEndian};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::str::{self, FromStr};
+
+/// Furthest a block can be from the origin along each axis; farther out,
+/// world generation and chunk arithmetic would overflow.
+pub const MAX_BLOCK_COORD: i64 = 1 << 40;
+/// Furthest a chunk can be from the origin along each axis.
+pub const MAX_CHUNK_COORD: i64 = MAX_BLOCK_COORD / 32;
+
+const BLOCK_COORDS: RangeInclusive<i64> = -MAX_BLOCK_COORD..=MAX_BLOCK_COORD;
+const CHUNK_COORDS: RangeInclusive<i64> = -MAX_CHUNK_COORD..=MAX_CHUNK_COORD;
+const POSITIONS: RangeInclusive<f64> = -(MAX_BLOCK_COORD as f64)..=MAX_BLOCK_COORD as f64;
+/* anything but infinities and NaN */
+const ANGLES: RangeInclusive<f64> = f64::MIN..=f64::MAX;
+
+/// Messages sent by the client.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientMessage {
+    /// `V,version`
+    Version(u32),
+    /// `C,p,q,r[,key]`
+    Chunk { p: i64, q: i64, r: i64, key: Option<i64> },
+    /// `P,x,y,z,rx,ry`
+    Position { x: f64, y: f64, z: f64, rx: f64, ry: f64 },
+    /// `B,x,y,z,w`
+    Block { x: i64, y: i64, z: i64, w: u8 },
+    /// `T,text`
+    Talk(String),
+}
+
+/// Messages sent by the server.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServerMessage {
+    /// `N,id,nick`
+    Nick { id: usize, nick: String },
+    /// `T,text`
+    Talk(String),
+    /// `P,id,x,y,z,rx,ry`: another player's position
+    Position { id: usize, x: f64, y: f64, z: f64, rx: f64, ry: f64 },
+    /// `U,id,x,y,z,rx,ry`: the receiving player's own id and position
+    You { id: usize, x: f64, y: f64, z: f64, rx: f64, ry: f64 },
+    /// `E,time,day_length`
+    Time { time: usize, day_length: usize },
+    /// `D,id`
+    Disconnect { id: usize },
+    /// `B,x,y,z,w`
+    Block { x: i64, y: i64, z: i64, w: u8 },
+    /// `C` followed by p, q and r as big-endian i64 and the compressed chunk
+    Chunk { p: i64, q: i64, r: i64, data: Vec<u8> },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProtocolError {
+    Empty,
+    NotUtf8,
+    UnknownType(String),
+    MissingField(&'static str),
+    InvalidField(&'static str, String),
+    TrailingFields(usize),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::Empty => write!(f, "empty message"),
+            ProtocolError::NotUtf8 => write!(f, "message is not valid UTF-8"),
+            ProtocolError::UnknownType(t) => write!(f, "unknown message type {:?}", t),
+            ProtocolError::MissingField(name) => write!(f, "missing field {}", name),
+            ProtocolError::InvalidField(name, v) => write!(f, "invalid value {:?} for field {}", v, name),
+            ProtocolError::TrailingFields(n) => write!(f, "{} unexpected trailing fields", n),
+        }
+    }
+}
+
+impl Error for ProtocolError {}
+
+impl From<ProtocolError> for io::Error {
+    fn from(err: ProtocolError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+struct Fields<'a> {
+    fields: str::Split<'a, char>,
+}
+
+impl<'a> Fields<'a> {
+    fn next<T: FromStr>(&mut self, name: &'static str) -> Result<T, ProtocolError> {
+        let s = self.fields.next().ok_or(ProtocolError::MissingField(name))?;
+        s.trim().parse().map_err(|_| ProtocolError::InvalidField(name, s.to_string()))
+    }
+    /// Like `next`, but values outside `range` are invalid too.
+    fn next_in<T: FromStr + PartialOrd>(&mut self, name: &'static str, range: RangeInclusive<T>) -> Result<T, ProtocolError> {
+        let s = self.fields.next().ok_or(ProtocolError::MissingField(name))?;
+        match s.trim().parse() {
+            Ok(v) if range.contains(&v) => Ok(v),
+            _ => Err(ProtocolError::InvalidField(name, s.to_string())),
+        }
+    }
+    fn next_opt<T: FromStr>(&mut self, name: &'static str) -> Result<Option<T>, ProtocolError> {
+        match self.fields.next() {
+            None => Ok(None),
+            Some(s) => s.trim().parse().map(Some).map_err(|_| ProtocolError::InvalidField(name, s.to_string())),
+        }
+    }
+    fn end(mut self) -> Result<(), ProtocolError> {
+        match self.fields.by_ref().count() {
+            0 => Ok(()),
+            n => Err(ProtocolError::TrailingFields(n)),
+        }
+    }
+}
+
+impl ClientMessage {
+    pub fn decode(msg: &[u8]) -> Result<ClientMessage, ProtocolError> {
+        let msg = str::from_utf8(msg).map_err(|_| ProtocolError::NotUtf8)?;
+        if msg.is_empty() {
+            return Err(ProtocolError::Empty);
+        }
+        let mut fields = msg.split(',');
+        /* split always yields at least one string */
+        let kind = fields.next().unwrap();
+        if kind == "T" {
+            /* the text may contain commas of its own */
+            return Ok(ClientMessage::Talk(msg.get(2..).unwrap_or("").to_string()));
+        }
+        let mut f = Fields { fields };
+        let m = match kind {
+            "V" => ClientMessage::Version(f.next("version")?),
+            "C" => ClientMessage::Chunk {
+                p: f.next_in("p", CHUNK_COORDS)?,
+                q: f.next_in("q", CHUNK_COORDS)?,
+                r: f.next_in("r", CHUNK_COORDS)?,
+                key: f.next_opt("key")?,
+            },
+            "P" => ClientMessage::Position {
+                x: f.next_in("x", POSITIONS)?,
+                y: f.next_in("y", POSITIONS)?,
+                z: f.next_in("z", POSITIONS)?,
+                rx: f.next_in("rx", ANGLES)?,
+                ry: f.next_in("ry", ANGLES)?,
+            },
+            "B" => ClientMessage::Block {
+                x: f.next_in("x", BLOCK_COORDS)?,
+                y: f.next_in("y", BLOCK_COORDS)?,
+                z: f.next_in("z", BLOCK_COORDS)?,
+                w: f.next("w")?,
+            },
+            k => return Err(ProtocolError::UnknownType(k.to_string())),
+        };
+        f.end()?;
+        Ok(m)
+    }
+}
+
+impl ServerMessage {
+    /// Encodes the message body, without the length prefix.
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            ServerMessage::Nick { id, nick } => format!("N,{},{}", id, nick).into_bytes(),
+            ServerMessage::Talk(text) => format!("T,{}", text).into_bytes(),
+            ServerMessage::Position { id, x, y, z, rx, ry } =>
+                format!("P,{},{},{},{},{},{}", id, x, y, z, rx, ry).into_bytes(),
+            ServerMessage::You { id, x, y, z, rx, ry } =>
+                format!("U,{},{},{},{},{},{}", id, x, y, z, rx, ry).into_bytes(),
+            ServerMessage::Time { time, day_length } => format!("E,{},{}", time, day_length).into_bytes(),
+            ServerMessage::Disconnect { id } => format!("D,{}", id).into_bytes(),
+            ServerMessage::Block { x, y, z, w } => format!("B,{},{},{},{}", x, y, z, w).into_bytes(),
+            ServerMessage::Chunk { p, q, r, data } => {
+                let mut msg = Vec::with_capacity(1 + 8 * 3 + data.len());
+                msg.push(b'C');
+                msg.write_i64::<NetworkEndian>(*p).unwrap();
+                msg.write_i64::<NetworkEndian>(*q).unwrap();
+                msg.write_i64::<NetworkEndian>(*r).unwrap();
+                msg.extend_from_slice(data);
+                msg
+            }
+        }
+    }
+    /// Writes the message preceded by its length.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let body = self.encode();
+        w.write_u32::<NetworkEndian>(body.len() as u32)?;
+        w.write_all(&body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_messages() {
+        assert_eq!(ClientMessage::decode(b"V,1"), Ok(ClientMessage::Version(1)));
+        assert_eq!(ClientMessage::decode(b"C,1,-2,3"), Ok(ClientMessage::Chunk { p: 1, q: -2, r: 3, key: None }));
+        assert_eq!(ClientMessage::decode(b"C,1,-2,3,4"), Ok(ClientMessage::Chunk { p: 1, q: -2, r: 3, key: Some(4) }));
+        assert_eq!(ClientMessage::decode(b"P,1.5,2,3,0.25,-1"),
+            Ok(ClientMessage::Position { x: 1.5, y: 2.0, z: 3.0, rx: 0.25, ry: -1.0 }));
+        assert_eq!(ClientMessage::decode(b"B,1,2,-3,4"), Ok(ClientMessage::Block { x: 1, y: 2, z: -3, w: 4 }));
+        assert_eq!(ClientMessage::decode(b"T,hello, world"), Ok(ClientMessage::Talk("hello, world".to_string())));
+    }
+
+    #[test]
+    fn decodes_talk_without_comma() {
+        assert_eq!(ClientMessage::decode(b"T"), Ok(ClientMessage::Talk(String::new())));
+    }
+
+    #[test]
+    fn rejects_empty_message() {
+        assert_eq!(ClientMessage::decode(b""), Err(ProtocolError::Empty));
+    }
+
+    #[test]
+    fn rejects_invalid_utf8() {
+        assert_eq!(ClientMessage::decode(b"T,\xff"), Err(ProtocolError::NotUtf8));
+    }
+
+    #[test]
+    fn rejects_unknown_type() {
+        assert_eq!(ClientMessage::decode(b"X,1"), Err(ProtocolError::UnknownType("X".to_string())));
+    }
+
+    #[test]
+    fn rejects_missing_fields() {
+        assert_eq!(ClientMessage::decode(b"V"), Err(ProtocolError::MissingField("version")));
+        assert_eq!(ClientMessage::decode(b"C,1,2"), Err(ProtocolError::MissingField("r")));
+        assert_eq!(ClientMessage::decode(b"B,1,2,3"), Err(ProtocolError::MissingField("w")));
+    }
+
+    #[test]
+    fn rejects_invalid_fields() {
+        assert_eq!(ClientMessage::decode(b"V,one"), Err(ProtocolError::InvalidField("version", "one".to_string())));
+        assert_eq!(ClientMessage::decode(b"C,1,,3"), Err(ProtocolError::InvalidField("q", "".to_string())));
+        assert_eq!(ClientMessage::decode(b"B,1,2,3,256"), Err(ProtocolError::InvalidField("w", "256".to_string())));
+        assert_eq!(ClientMessage::decode(b"P,1,2,3,4,x"), Err(ProtocolError::InvalidField("ry", "x".to_string())));
+    }
+
+    #[test]
+    fn rejects_out_of_range_coordinates() {
+        assert_eq!(ClientMessage::decode(b"C,9223372036854775807,0,0"),
+            Err(ProtocolError::InvalidField("p", "9223372036854775807".to_string())));
+        assert_eq!(ClientMessage::decode(b"B,0,0,-9223372036854775808,1"),
+            Err(ProtocolError::InvalidField("z", "-9223372036854775808".to_string())));
+        assert_eq!(ClientMessage::decode(b"P,1e300,0,0,0,0"), Err(ProtocolError::InvalidField("x", "1e300".to_string())));
+        let max = format!("B,{},0,0,1", MAX_BLOCK_COORD);
+        assert!(ClientMessage::decode(max.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn rejects_non_finite_positions() {
+        assert_eq!(ClientMessage::decode(b"P,inf,0,0,0,0"), Err(ProtocolError::InvalidField("x", "inf".to_string())));
+        assert_eq!(ClientMessage::decode(b"P,0,NaN,0,0,0"), Err(ProtocolError::InvalidField("y", "NaN".to_string())));
+        assert_eq!(ClientMessage::decode(b"P,0,0,0,0,-inf"), Err(ProtocolError::InvalidField("ry", "-inf".to_string())));
+    }
+
+    #[test]
+    fn rejects_trailing_fields() {
+        assert_eq!(ClientMessage::decode(b"V,1,2"), Err(ProtocolError::TrailingFields(1)));
+        assert_eq!(ClientMessage::decode(b"C,1,2,3,4,5,6"), Err(ProtocolError::TrailingFields(2)));
+    }
+}
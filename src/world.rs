@@ -1,15 +1,27 @@
 mod block;
 mod chunk;
 mod coords;
+mod generator;
+mod journal;
+mod level;
+mod loader;
 mod map;
+mod palette;
+mod region;
+mod saver;
+mod storage;
 mod worldgen;
-use byteorder::{WriteBytesExt, NetworkEndian};
+use crate::clock::{TickClock, TICKS_PER_SECOND};
+use crate::config::Config;
+use crate::protocol::{ClientMessage, ServerMessage, MAX_CHUNK_COORD};
 use block::{Block, BlockId};
+use chunk::Chunk;
 use coords::Coords;
 use map::Map;
 use std::collections::HashMap;
 use std::io::{self, Write};
-use std::sync::{Arc, RwLock, RwLockWriteGuard};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 struct Player {
     nick: String,
@@ -18,170 +30,395 @@ struct Player {
     z: f64,
     rx: f64,
     ry: f64,
+    admin: bool,
+}
+
+/* what a client waits for a chunk to be loaded for */
+#[derive(Clone, Copy)]
+enum Wanted {
+    /* the chunk itself, asked for with C */
+    Chunk,
+    /* to change one of its blocks, asked for with B */
+    Block(Coords, Block),
+    /* what it is made of, asked for with /chunk */
+    Composition,
 }
 
 pub struct Server<T: Write> {
     players: HashMap<usize, (Player, Arc<RwLock<T>>)>,
     map: Map,
+    config: Config,
+    clock: TickClock,
+    ticks_since_save: usize,
+    autosave: bool,
+    /* clients waiting for each chunk being loaded, and what for; there is
+     * one entry per chunk the map was asked to load */
+    waiting: HashMap<Coords, Vec<(usize, Wanted)>>,
+    stopping: Option<String>,
 }
 
-fn write_raw_msg<T: Write>(w: &mut T, b: &[u8]) -> Result<(), io::Error> {
-    w.write_u32::<NetworkEndian>(b.len() as u32)?;
-    w.write_all(b)
+const ADMIN_COMMANDS: &[&str] = &["/tps", "/stop", "/save", "/save-off", "/save-on", "/chunk", "/seed"];
+
+/* what to send a client asking for a chunk; nothing if it is all air */
+fn chunk_message(cc: Coords, chunk: &Chunk) -> io::Result<Option<ServerMessage>> {
+    if chunk.is_air() {
+        println!("Chunk empty");
+        return Ok(None);
+    }
+    println!("Sending chunk");
+    let mut data = Vec::new();
+    chunk.write_to(&mut data)?;
+    Ok(Some(ServerMessage::Chunk { p: cc.0, q: cc.1, r: cc.2, data }))
 }
-fn write_msg<T: Write>(w: &mut T, s: &str) -> Result<(), io::Error> {
-    write_raw_msg(w, s.as_bytes())
+
+fn encode(msgs: &[ServerMessage]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for msg in msgs {
+        msg.write_to(&mut buf).unwrap();
+    }
+    buf
 }
 
 impl<T: Write> Server<T> {
-    pub fn new() -> Server<T> {
-        Server {
+    pub fn new(config: &Config) -> Result<Server<T>, io::Error> {
+        let map = Map::new(config)?;
+        Ok(Server {
             players: HashMap::new(),
-            map: Map::new(),
-        }
+            map,
+            config: config.clone(),
+            clock: TickClock::new(Instant::now()),
+            ticks_since_save: 0,
+            autosave: true,
+            waiting: HashMap::new(),
+            stopping: None,
+        })
     }
-    fn send_except<F: Fn(&mut T) -> Result<(), io::Error>>(&mut self, ex: usize, write: F) -> Result<(), io::Error> {
+    /* a client that fails to receive is dropped when its own connection errors out,
+     * so it must not prevent everyone else from getting the message */
+    fn send_except(&mut self, ex: usize, msgs: &[ServerMessage]) -> Result<(), io::Error> {
+        let buf = encode(msgs);
         for (id, (_, w)) in self.players.iter_mut() {
             if *id != ex {
-                let mut writer = w.write().unwrap();
-                write(&mut writer)?;
+                if let Err(e) = w.write().unwrap().write_all(&buf) {
+                    println!("Client {}: write error: {}", id, e);
+                }
             }
         }
         Ok(())
     }
-    fn client_writer(&mut self, ex: usize) -> RwLockWriteGuard<T> {
-        self.players.get_mut(&ex).unwrap().1.write().unwrap()
+    fn send_to(&mut self, id: usize, msgs: &[ServerMessage]) -> Result<(), io::Error> {
+        let buf = encode(msgs);
+        self.players[&id].1.write().unwrap().write_all(&buf)
     }
-    pub fn connect(&mut self, client: Arc<RwLock<T>>, id: usize) -> Result<(), io::Error> {
+    fn send_all(&mut self, msgs: &[ServerMessage]) -> Result<(), io::Error> {
+        let buf = encode(msgs);
+        for (id, (_, w)) in self.players.iter_mut() {
+            if let Err(e) = w.write().unwrap().write_all(&buf) {
+                println!("Client {}: write error: {}", id, e);
+            }
+        }
+        Ok(())
+    }
+    pub fn connect(&mut self, client: Arc<RwLock<T>>, id: usize, admin: bool) -> Result<(), io::Error> {
+        let [x, y, z] = self.map.spawn();
         let player = Player {
-            nick: format!("person{}", id),
-            x: 0.0, y: 0.0, z: 0.0,
+            nick: self.config.nick(id),
+            x, y, z,
             rx: 0.0, ry: 0.0,
+            admin,
         };
-        let mut msgs = Vec::new();
-        write_msg(&mut msgs, &format!("N,{},{}", id, player.nick))?;
-        write_msg(&mut msgs, &format!("T,{} has joined", player.nick))?;
-        write_msg(&mut msgs, &format!("P,{},0.0,0.0,0.0,0.0,0.0", id))?;
+        let msgs = [
+            ServerMessage::Nick { id, nick: player.nick.clone() },
+            ServerMessage::Talk(format!("{} has joined", player.nick)),
+            ServerMessage::Position { id, x: player.x, y: player.y, z: player.z, rx: player.rx, ry: player.ry },
+        ];
         self.players.insert(id, (player, client));
-        self.send_except(id, |w| w.write_all(&msgs))?;
-        msgs.clear();
-        write_msg(&mut msgs, &format!("U,{},0.0,0.0,0.0,0.0,0.0", id))?;
-        write_msg(&mut msgs, &format!("E,{},600", self.map.get_time() / 20))?;
+        self.send_except(id, &msgs)?;
+        let mut msgs = vec![
+            ServerMessage::You { id, x, y, z, rx: 0.0, ry: 0.0 },
+            ServerMessage::Time {
+                time: self.map.get_time() / TICKS_PER_SECOND as usize,
+                day_length: self.config.day_length,
+            },
+        ];
         for (i, (p, _)) in self.players.iter() {
             if *i != id {
-                write_msg(&mut msgs, &format!("P,{},{},{},{},{},{}", i, p.x, p.y, p.z, p.rx, p.ry))?;
-                write_msg(&mut msgs, &format!("N,{},{}", *i, p.nick))?;
+                msgs.push(ServerMessage::Position { id: *i, x: p.x, y: p.y, z: p.z, rx: p.rx, ry: p.ry });
+                msgs.push(ServerMessage::Nick { id: *i, nick: p.nick.clone() });
             }
         }
-        self.client_writer(id).write_all(&msgs)
+        self.send_to(id, &msgs)
     }
     pub fn disconnect(&mut self, id: usize) -> Result<(), io::Error> {
-        let mut msgs = Vec::new();
-        write_msg(&mut msgs, &format!("D,{}", id))?;
-        write_msg(&mut msgs, &format!("T,{} has left", self.players[&id].0.nick))?;
-        self.players.remove(&id);
-        self.map.save();
-        self.send_except(id, |w| w.write_all(&msgs))
+        let (player, _) = match self.players.remove(&id) {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let msgs = [
+            ServerMessage::Disconnect { id },
+            ServerMessage::Talk(format!("{} has left", player.nick)),
+        ];
+        self.send_except(id, &msgs)
+    }
+    /// Tells the client why it is being dropped, then disconnects it.
+    pub fn kick(&mut self, id: usize, reason: &str) -> Result<(), io::Error> {
+        println!("Kicking client {}: {}", id, reason);
+        if self.players.contains_key(&id) {
+            let msg = ServerMessage::Talk(format!("Disconnected: {}", reason));
+            if let Err(e) = self.send_to(id, &[msg]) {
+                println!("Client {}: write error: {}", id, e);
+            }
+        }
+        self.disconnect(id)
     }
-    pub fn tick(&mut self, secs: f32) {
-        self.map.tick((secs * 20.0).round() as usize);
-        if secs > 30.0 {
-            self.map.save();
+    fn save(&mut self) {
+        if let Err(e) = self.map.save() {
+            println!("Error while saving: {}", e);
+        }
+    }
+    /* changed chunks out of view are saved first, so that the next pass can unload them */
+    fn unload_chunks(&mut self) {
+        let players: Vec<Coords> = self.players.values()
+            .map(|(p, _)| Coords(p.x.floor() as i64, p.y.floor() as i64, p.z.floor() as i64).chunk())
+            .collect();
+        if self.map.unload(&players, self.config.view_distance, self.config.max_loaded_chunks) && self.autosave {
+            self.save();
+        }
+    }
+    /// Asks the event loop to shut the server down.
+    pub fn stop(&mut self, reason: &str) {
+        if self.stopping.is_none() {
+            println!("Stopping: {}", reason);
+            self.stopping = Some(reason.to_string());
+        }
+    }
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.is_some()
+    }
+    /// Tells everyone the server is going down and saves the world.
+    pub fn shutdown(&mut self) -> Result<(), io::Error> {
+        let reason = self.stopping.clone().unwrap_or_else(|| "shutting down".to_string());
+        self.send_all(&[ServerMessage::Talk(format!("Server stopping: {}", reason))])?;
+        self.map.save()?;
+        self.map.wait_for_save()
+    }
+    /// How long the event loop may wait before calling `update` again.
+    pub fn time_to_next_tick(&self) -> Duration {
+        self.clock.until_next_tick(Instant::now())
+    }
+    /* how far chunk cc is from the player, in squared chunks */
+    fn distance(&self, id: usize, cc: Coords) -> i64 {
+        let player = &self.players[&id].0;
+        let here = Coords(player.x.floor() as i64, player.y.floor() as i64, player.z.floor() as i64).chunk();
+        [(cc.0, here.0), (cc.1, here.1), (cc.2, here.2)].iter()
+            .fold(0i64, |d, &(a, b)| d.saturating_add(a.saturating_sub(b).saturating_pow(2)))
+    }
+    /* does what the client wants with chunk cc now if it is loaded, or else
+     * once it is, without ever loading it on this thread */
+    fn with_chunk(&mut self, id: usize, cc: Coords, wanted: Wanted) -> Result<(), io::Error> {
+        let distance = self.distance(id, cc);
+        if self.map.request_chunk(cc, distance).is_some() {
+            return self.answer(id, cc, wanted);
+        }
+        let waiting = self.waiting.entry(cc).or_default();
+        match wanted {
+            /* the later change to a block wins anyway */
+            Wanted::Block(c, _) => waiting.retain(|&(_, w)| !matches!(w, Wanted::Block(b, _) if b == c)),
+            Wanted::Chunk | Wanted::Composition => {
+                let same = |w: Wanted| matches!((w, wanted), (Wanted::Chunk, Wanted::Chunk) | (Wanted::Composition, Wanted::Composition));
+                if waiting.iter().any(|&(i, w)| i == id && same(w)) {
+                    return Ok(());
+                }
+            }
+        }
+        waiting.push((id, wanted));
+        Ok(())
+    }
+    /* does what the client wanted with chunk cc, which is loaded */
+    fn answer(&mut self, id: usize, cc: Coords, wanted: Wanted) -> Result<(), io::Error> {
+        match wanted {
+            Wanted::Chunk => {
+                match chunk_message(cc, self.map.loaded_chunk(cc).unwrap())? {
+                    Some(msg) => self.send_to(id, &[msg]),
+                    None => Ok(()),
+                }
+            }
+            Wanted::Block(c, block) => {
+                self.map.replace_block(c, block);
+                let now = self.map.loaded_chunk(cc).unwrap().get_block(c.in_chunk());
+                println!("{:?} of chunk {:?} is now {}", (c.0, c.1, c.2), cc, now.matter);
+                self.send_all(&[ServerMessage::Block { x: c.0, y: c.1, z: c.2, w: block.matter }])
+            }
+            Wanted::Composition => {
+                let composition: Vec<String> = self.map.loaded_chunk(cc).unwrap().composition()
+                    .map(|(id, n)| format!("{} of block {}", n, id))
+                    .collect();
+                let msg = ServerMessage::Talk(format!("Chunk {} {} {}: {}", cc.0, cc.1, cc.2, composition.join(", ")));
+                self.send_to(id, &[msg])
+            }
+        }
+    }
+    /* tells the client that chunk cc couldn't be loaded for what it wanted */
+    fn refuse(&mut self, id: usize, cc: Coords, wanted: Wanted, err: &io::Error) -> Result<(), io::Error> {
+        let msg = match wanted {
+            Wanted::Block(c, _) => format!("Could not change block {} {} {}: {}", c.0, c.1, c.2, err),
+            Wanted::Chunk | Wanted::Composition => format!("Could not load chunk {} {} {}: {}", cc.0, cc.1, cc.2, err),
+        };
+        self.send_to(id, &[ServerMessage::Talk(msg)])
+    }
+    /* answers the clients waiting for chunks that were just loaded */
+    fn send_loaded_chunks(&mut self) {
+        for (cc, result) in self.map.poll_chunks() {
+            let waiting = match self.waiting.remove(&cc) {
+                Some(waiting) => waiting,
+                None => continue,
+            };
+            if result.is_ok() && self.map.loaded_chunk(cc).is_none() {
+                /* unloaded again before anyone got it */
+                self.waiting.insert(cc, waiting);
+                self.map.request_chunk(cc, 0);
+                continue;
+            }
+            for (id, wanted) in waiting {
+                let sent = match (&result, self.players.contains_key(&id)) {
+                    (Ok(()), true) => self.answer(id, cc, wanted),
+                    /* blocks are changed even if whoever changed them has left */
+                    (Ok(()), false) if matches!(wanted, Wanted::Block(..)) => self.answer(id, cc, wanted),
+                    (Err(e), true) => self.refuse(id, cc, wanted, e),
+                    _ => Ok(()),
+                };
+                if let Err(e) = sent {
+                    println!("Client {}: write error: {}", id, e);
+                }
+            }
+        }
+    }
+    /// Runs a game tick if one is due.
+    pub fn update(&mut self) {
+        self.send_loaded_chunks();
+        if self.clock.start_tick(Instant::now()) {
+            self.tick();
+        }
+    }
+    fn tick(&mut self) {
+        self.map.tick(1);
+        if let Err(e) = self.map.poll_save() {
+            println!("Error while saving: {}", e);
+        }
+        self.ticks_since_save += 1;
+        if self.ticks_since_save >= self.config.autosave_interval * TICKS_PER_SECOND as usize {
+            self.ticks_since_save = 0;
+            if self.autosave && self.map.has_changes() {
+                println!("Autosaving");
+                self.save();
+            }
+        }
+        if self.ticks_since_save.is_multiple_of(TICKS_PER_SECOND as usize) {
+            self.unload_chunks();
         }
     }
     pub fn command(&mut self, id: usize, cmd: &str) -> Result<(), io::Error> {
         let fields: Vec<&str> = cmd.split_whitespace().collect();
         match (fields[0], fields.len()) {
             ("/nick", 2) => {
-                let mut msgs = Vec::new();
-                write_msg(&mut msgs, &format!("T,{} is now {}", &self.players[&id].0.nick, fields[1]))?;
-                write_msg(&mut msgs, &format!("N,{},{}", id, fields[1]))?;
+                let msgs = [
+                    ServerMessage::Talk(format!("{} is now {}", &self.players[&id].0.nick, fields[1])),
+                    ServerMessage::Nick { id, nick: fields[1].to_string() },
+                ];
                 self.players.get_mut(&id).unwrap().0.nick = fields[1].to_string();
-                self.send_except(id, |w| w.write_all(&msgs))?;
-                self.client_writer(id).write_all(&msgs)
+                self.send_all(&msgs)
             }
             ("/nick", 1) => {
-                let msg = format!("T,You are {}", &self.players[&id].0.nick);
-                write_msg::<T>(&mut self.client_writer(id), &msg)
+                let msg = ServerMessage::Talk(format!("You are {}", &self.players[&id].0.nick));
+                self.send_to(id, &[msg])
+            }
+            (cmd, _) if ADMIN_COMMANDS.contains(&cmd) && !self.players[&id].0.admin => {
+                let msg = ServerMessage::Talk(format!("{} is only available to admins", cmd));
+                self.send_to(id, &[msg])
+            }
+            ("/tps", 1) => {
+                let msg = ServerMessage::Talk(format!("{:.2} ticks per second", self.clock.tps()));
+                self.send_to(id, &[msg])
+            }
+            ("/save", 1) => {
+                self.save();
+                self.ticks_since_save = 0;
+                self.send_to(id, &[ServerMessage::Talk("Saving the world".to_string())])
+            }
+            ("/save-off", 1) => {
+                self.autosave = false;
+                self.send_to(id, &[ServerMessage::Talk("Autosave is off".to_string())])
+            }
+            ("/save-on", 1) => {
+                self.autosave = true;
+                self.send_to(id, &[ServerMessage::Talk("Autosave is on".to_string())])
+            }
+            ("/chunk", 1) | ("/chunk", 4) => {
+                let cc = if fields.len() == 4 {
+                    let parse = |s: &str| s.parse::<i64>().ok().filter(|c| c.abs() <= MAX_CHUNK_COORD);
+                    match (parse(fields[1]), parse(fields[2]), parse(fields[3])) {
+                        (Some(p), Some(q), Some(r)) => Coords(p, q, r),
+                        _ => return self.send_to(id, &[ServerMessage::Talk("Usage: /chunk [p q r]".to_string())]),
+                    }
+                } else {
+                    let player = &self.players[&id].0;
+                    Coords(player.x.floor() as i64, player.y.floor() as i64, player.z.floor() as i64).chunk()
+                };
+                self.with_chunk(id, cc, Wanted::Composition)
+            }
+            ("/seed", 1) => {
+                let msg = ServerMessage::Talk(format!("Seed: {}", self.map.seed()));
+                self.send_to(id, &[msg])
+            }
+            ("/stop", 1) => {
+                let reason = format!("stopped by {}", self.players[&id].0.nick);
+                self.stop(&reason);
+                Ok(())
             }
             (cmd, args) => {
-                write_msg::<T>(&mut self.client_writer(id), &format!("T,Unknown command: {}[{}]", cmd, args-1))
+                let msg = ServerMessage::Talk(format!("Unknown command: {}[{}]", cmd, args-1));
+                self.send_to(id, &[msg])
             }
         }
     }
     pub fn process_message(&mut self, id: usize, msg: &[u8]) -> Result<(), io::Error> {
-        let smsg = String::from_utf8_lossy(msg);
-        let fields: Vec<&str> = smsg.split(',').collect();
-        match fields[0] {
-            "V" => {
-                println!("{}: {}", id, smsg);
-                if fields[1] != "2" {
-                    Err(io::Error::new(io::ErrorKind::Other, "Incompatible version"))
+        let msg = ClientMessage::decode(msg)?;
+        match msg {
+            ClientMessage::Version(v) => {
+                println!("{}: {:?}", id, msg);
+                if v != 2 {
+                    Err(io::Error::other("Incompatible version"))
                 } else {
                     Ok(())
                 }
             }
-            "C" => {
-                let p: i64 = fields[1].parse().unwrap();
-                let q: i64 = fields[2].parse().unwrap();
-                let r: i64 = fields[3].parse().unwrap();
-                let _key: i64 = fields[3].parse().unwrap();
-                println!("{}: {}", id, smsg);
-                let chunk = self.map.get_chunk(Coords(p, q, r));
-                if chunk.is_air() {
-                    println!("Chunk empty");
-                    return Ok(());
-                }
-                println!("Sending chunk");
-                let mut blocks = Vec::with_capacity(1+8+8 + 32*32*32);
-                blocks.write_u8('C' as u8).unwrap();
-                blocks.write_i64::<NetworkEndian>(p).unwrap();
-                blocks.write_i64::<NetworkEndian>(q).unwrap();
-                blocks.write_i64::<NetworkEndian>(r).unwrap();
-                chunk.write_to(&mut blocks).unwrap();
-                write_raw_msg::<T>(&mut self.client_writer(id), &blocks)
-            }
-            "P" => {
+            ClientMessage::Chunk { p, q, r, .. } => {
+                println!("{}: {:?}", id, msg);
+                self.with_chunk(id, Coords(p, q, r), Wanted::Chunk)
+            }
+            ClientMessage::Position { x, y, z, rx, ry } => {
                 let (player, _) = self.players.get_mut(&id).unwrap();
-                player.x = fields[1].parse().unwrap();
-                player.y = fields[2].parse().unwrap();
-                player.z = fields[3].parse().unwrap();
-                player.rx = fields[4].parse().unwrap();
-                player.ry = fields[5].parse().unwrap();
-                let mut msg = Vec::new();
-                write_msg(&mut msg, &format!("P,{},{},{},{},{},{}\n", id, player.x, player.y, player.z, player.rx, player.ry))?;
-                self.send_except(id, |w| w.write_all(&msg))
-            }
-            "B" => {
-                let x: i64 = fields[1].parse().unwrap();
-                let y: i64 = fields[2].parse().unwrap();
-                let z: i64 = fields[3].parse().unwrap();
-                let w: u8 = fields[4].parse().unwrap();
+                player.x = x;
+                player.y = y;
+                player.z = z;
+                player.rx = rx;
+                player.ry = ry;
+                self.send_except(id, &[ServerMessage::Position { id, x, y, z, rx, ry }])
+            }
+            ClientMessage::Block { x, y, z, w } => {
                 let c = Coords(x, y, z);
-                println!("{}: {}", id, smsg);
-                self.map.replace_block(c, Block::new(w as BlockId));
-                println!("{:?} of chunk {:?} is now {}", (x, y, z), Coords(x, y, z).chunk(), self.map.get_block(c).matter as u8);
-                let smsg = format!("B,{},{},{},{}\n", x, y, z, w);
-                println!("{}", &smsg);
-                let mut msg = Vec::new();
-                write_msg(&mut msg, &smsg)?;
-                self.send_except(id, |w| w.write_all(&msg))?;
-                self.client_writer(id).write_all(&msg)
-            }
-            "T" => {
-                let (_, chat) = smsg.split_at(2);
-                if chat.starts_with("/") {
-                    self.command(id, chat)
+                println!("{}: {:?}", id, msg);
+                self.with_chunk(id, c.chunk(), Wanted::Block(c, Block::new(w as BlockId)))
+            }
+            ClientMessage::Talk(chat) => {
+                if chat.starts_with('/') {
+                    self.command(id, &chat)
                 } else {
-                    let mut msg = Vec::new();
                     println!("Chat: [{}] {}", self.players[&id].0.nick, chat);
-                    write_msg(&mut msg, &format!("T,[{}] {}", self.players[&id].0.nick, chat))?;
-                    self.send_except(id, |w| w.write_all(&msg))?;
-                    self.client_writer(id).write_all(&msg)
+                    let msg = ServerMessage::Talk(format!("[{}] {}", self.players[&id].0.nick, chat));
+                    self.send_all(&[msg])
                 }
             }
-            m => panic!("{} message not implemented", m),
         }
     }
 }
-
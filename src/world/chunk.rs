@@ -1,31 +1,108 @@
 use super::block::{Block, BlockId};
 use super::coords::Coords;
-use super::worldgen::Worldgen;
+use super::palette::Blocks;
+use super::generator::WorldGenerator;
 use flate2::write::DeflateEncoder;
 use flate2::bufread::DeflateDecoder;
 use flate2::Compression;
-use std::io::{BufRead, Read, Write};
+use byteorder::{ByteOrder, NetworkEndian};
+use std::error::Error;
+use std::fmt;
+use std::io::{Read, Write};
 use std::io;
+use std::sync::{Arc, OnceLock};
 
-fn chunk_coords(b: usize) -> Coords {
+/* saved chunks start with this, then the format version, the width of a
+ * block id in bytes, the length of a side of the chunk, and a CRC32 of the
+ * compressed blocks that follow */
+const MAGIC: &[u8; 4] = b"CfCh";
+const FORMAT_VERSION: u8 = 1;
+const HEADER: usize = 4 + 1 + 1 + 1 + 4;
+const BLOCKS: usize = 32*32*32;
+
+/// Why a saved chunk couldn't be loaded.
+#[derive(Debug)]
+pub enum ChunkError {
+    Truncated,
+    UnknownVersion(u8),
+    UnsupportedBlockWidth(u8),
+    WrongSize(u8),
+    BadChecksum,
+    Corrupt(io::Error),
+    WrongBlockCount(usize),
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChunkError::Truncated => write!(f, "chunk header is cut short"),
+            ChunkError::UnknownVersion(v) => write!(f, "unknown chunk format version {}", v),
+            ChunkError::UnsupportedBlockWidth(w) => write!(f, "unsupported block id width of {} bytes", w),
+            ChunkError::WrongSize(n) => write!(f, "chunk is {0}×{0}×{0} instead of 32×32×32", n),
+            ChunkError::BadChecksum => write!(f, "chunk checksum doesn't match"),
+            ChunkError::Corrupt(e) => write!(f, "chunk data can't be decompressed: {}", e),
+            ChunkError::WrongBlockCount(n) => write!(f, "chunk has {} blocks instead of {}", n, BLOCKS),
+        }
+    }
+}
+
+impl Error for ChunkError {}
+
+impl From<ChunkError> for io::Error {
+    fn from(err: ChunkError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Whether `data` is a chunk saved before chunks had a header, and should
+/// be saved again in the current format.
+pub fn is_headerless(data: &[u8]) -> bool {
+    !data.starts_with(MAGIC)
+}
+
+/// Index in a chunk's blocks of the block at `c`, in chunk coordinates.
+/// Blocks are kept, generated, saved and sent to clients in this order.
+pub fn block_index(c: Coords) -> usize {
+    (c.0 + c.1 * 32 + c.2 * 32 * 32) as usize
+}
+
+/// Chunk coordinates of the block at index `b`; the inverse of `block_index`.
+pub fn block_coords(b: usize) -> Coords {
     Coords(
         (b % 32) as i64,
-        ((b / (32 * 32)) % 32) as i64,
         ((b / 32) % 32) as i64,
+        (b / (32 * 32)) as i64,
     )
 }
 
+#[derive(Clone)]
 pub struct Chunk {
-    blocks: [Block; 32*32*32],
+    blocks: Blocks,
+    /* the compressed blocks, once needed; shared with the copies being saved */
+    compressed: Arc<OnceLock<Vec<u8>>>,
     unchanged: bool,
-    air: bool,
+    /* how many blocks of each kind there are */
+    counts: [u16; 256],
+}
+
+fn count_blocks(blocks: &Blocks) -> [u16; 256] {
+    let mut counts = [0; 256];
+    match blocks {
+        Blocks::Single(b) => counts[b.matter as usize] = BLOCKS as u16,
+        _ => for b in blocks.iter() {
+            counts[b.matter as usize] += 1;
+        },
+    }
+    counts
 }
 
+#[cfg(test)]
 pub struct Iter<'a> {
     chunk: &'a Chunk,
     block: usize,
 }
 
+#[cfg(test)]
 impl<'a> Iterator for Iter<'a> {
     type Item = (Coords, Block);
     fn next(&mut self) -> Option<Self::Item> {
@@ -34,71 +111,180 @@ impl<'a> Iterator for Iter<'a> {
         }
         let b = self.block;
         self.block += 1;
-        Some((chunk_coords(b), self.chunk.blocks[b]))
+        Some((block_coords(b), self.chunk.blocks.get(b)))
     }
 }
 
 impl Chunk {
-    pub fn new(worldgen: &Worldgen, c: Coords) -> Chunk {
+    pub fn new(worldgen: &dyn WorldGenerator, c: Coords) -> Chunk {
         let first_block = Coords(c.0 * 32, c.1 * 32, c.2 * 32);
-        let blocks = worldgen.whole_chunk(first_block);
-        let air = worldgen.air_chunk(first_block);
+        let blocks = worldgen.generate_chunk(first_block);
         Chunk {
-            blocks: blocks,
+            counts: count_blocks(&blocks),
+            blocks,
+            compressed: Arc::new(OnceLock::new()),
             unchanged: true,
-            air: air,
         }
     }
-    pub fn load<T: BufRead>(_c: Coords, r: T) -> Chunk {
-        let mut blocks = [Block::AIR; 32*32*32];
-        let mut i = 0;
-        let mut air = true;
-        for id in DeflateDecoder::new(r).bytes().map(|b| b.unwrap()) {
-            let b = Block::new(id as BlockId);
-            if b.matter != Block::AIR.matter {
-                air = false;
+    /// Loads a chunk saved by `store`, or by older versions as bare
+    /// compressed blocks.
+    pub fn load(_c: Coords, data: &[u8]) -> Result<Chunk, ChunkError> {
+        let compressed = if is_headerless(data) {
+            data
+        } else {
+            if data.len() < HEADER {
+                return Err(ChunkError::Truncated);
+            }
+            if data[4] != FORMAT_VERSION {
+                return Err(ChunkError::UnknownVersion(data[4]));
             }
-            if i >= 32*32*32 {
-                panic!("Invalid chunk");
+            if data[5] != 1 {
+                return Err(ChunkError::UnsupportedBlockWidth(data[5]));
             }
-            blocks[i] = b;
-            i += 1;
+            if data[6] != 32 {
+                return Err(ChunkError::WrongSize(data[6]));
+            }
+            let compressed = &data[HEADER..];
+            if crc32fast::hash(compressed) != NetworkEndian::read_u32(&data[7..HEADER]) {
+                return Err(ChunkError::BadChecksum);
+            }
+            compressed
+        };
+        let mut data = Vec::new();
+        DeflateDecoder::new(compressed).take(BLOCKS as u64 + 1).read_to_end(&mut data)
+            .map_err(ChunkError::Corrupt)?;
+        if data.len() != BLOCKS {
+            return Err(ChunkError::WrongBlockCount(data.len()));
         }
-        Chunk {
-            blocks: blocks,
-            air: air,
+        let blocks = Blocks::from_fn(|i| Block::new(data[i] as BlockId));
+        Ok(Chunk {
+            counts: count_blocks(&blocks),
+            blocks,
+            compressed: Arc::new(OnceLock::new()),
             unchanged: true,
-        }
+        })
     }
-    pub fn iter<'a>(&'a self) -> Iter<'a> {
-        Iter { chunk: &self, block: 0 }
+    #[cfg(test)]
+    pub fn iter(&self) -> Iter<'_> {
+        Iter { chunk: self, block: 0 }
     }
     pub fn replace_block(&mut self, c: Coords, block: Block) {
         println!("{:?} <- {:?}", c, block);
         assert!(Coords(0, 0, 0) <= c);
         assert!(c < Coords(32, 32, 32));
         self.unchanged = false;
-        if block.matter != Block::AIR.matter {
-            self.air = false;
-        }
-        self.blocks[c.0 as usize + c.1 as usize * 32 + c.2 as usize * 32 * 32] = block;
+        self.compressed = Arc::new(OnceLock::new());
+        let i = block_index(c);
+        self.counts[self.blocks.get(i).matter as usize] -= 1;
+        self.counts[block.matter as usize] += 1;
+        self.blocks.set(i, block);
     }
     pub fn get_block(&self, c: Coords) -> Block {
         assert!(Coords(0, 0, 0) <= c && c < Coords(32, 32, 32));
-        self.blocks[c.0 as usize + c.1 as usize * 32 + c.2 as usize * 32 * 32]
+        self.blocks.get(block_index(c))
     }
     pub fn is_unchanged(&self) -> bool {
         self.unchanged
     }
+    /// Called once the chunk is being saved.
+    pub fn mark_unchanged(&mut self) {
+        self.unchanged = true;
+    }
+    pub fn mark_changed(&mut self) {
+        self.unchanged = false;
+    }
     pub fn is_air(&self) -> bool {
-        self.air
+        self.counts[Block::AIR.matter as usize] as usize == BLOCKS
+    }
+    /// How many blocks of each kind the chunk has, leaving out the kinds
+    /// it has none of.
+    pub fn composition(&self) -> impl Iterator<Item = (BlockId, usize)> + '_ {
+        self.counts.iter().enumerate().filter(|&(_, &n)| n != 0).map(|(id, &n)| (id as BlockId, n as usize))
     }
+    /// Writes the chunk in the format `load` reads.
+    pub fn store<T: Write>(&self, w: &mut T) -> io::Result<()> {
+        let compressed = self.compressed();
+        let mut header = [0; HEADER];
+        header[..4].copy_from_slice(MAGIC);
+        header[4] = FORMAT_VERSION;
+        header[5] = 1;
+        header[6] = 32;
+        NetworkEndian::write_u32(&mut header[7..], crc32fast::hash(compressed));
+        w.write_all(&header)?;
+        w.write_all(compressed)
+    }
+    /// Writes the compressed blocks, as sent to clients.
     pub fn write_to<T: Write>(&self, w: &mut T) -> io::Result<()> {
-        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
-        for b in self.blocks.iter().map(|b| b.matter as u8) {
-            encoder.write(&[b])?;
-        }
-        w.write_all(&encoder.finish().unwrap())
+        w.write_all(self.compressed())
+    }
+    /// The compressed blocks, compressed only once until the chunk changes.
+    fn compressed(&self) -> &[u8] {
+        self.compressed.get_or_init(|| {
+            let ids: Vec<BlockId> = self.blocks.iter().map(|b| b.matter).collect();
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            /* writing to memory can't fail */
+            encoder.write_all(&ids).unwrap();
+            encoder.finish().unwrap()
+        })
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::generator::Void;
+    use proptest::prelude::*;
+
+    fn edited_chunk(edits: &[((i64, i64, i64), BlockId)]) -> Chunk {
+        let mut chunk = Chunk::new(&Void, Coords(0, 0, 0));
+        for &((x, y, z), id) in edits {
+            chunk.replace_block(Coords(x, y, z), Block::new(id));
+        }
+        chunk
+    }
+
+    fn assert_same_blocks(a: &Chunk, b: &Chunk) {
+        for (c, block) in a.iter() {
+            assert_eq!(block.matter, a.get_block(c).matter);
+            assert_eq!(block.matter, b.get_block(c).matter);
+        }
+    }
+
+    #[test]
+    fn block_coords_inverts_block_index() {
+        for b in 0..BLOCKS {
+            let c = block_coords(b);
+            assert!(Coords(0, 0, 0) <= c && c < Coords(32, 32, 32));
+            assert_eq!(block_index(c), b);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn iter_matches_get_block(edits in prop::collection::vec(((0..32i64, 0..32i64, 0..32i64), any::<BlockId>()), 0..200)) {
+            let chunk = edited_chunk(&edits);
+            for &((x, y, z), _) in &edits {
+                let c = Coords(x, y, z);
+                let (at, block) = chunk.iter().nth(block_index(c)).unwrap();
+                prop_assert_eq!(at, c);
+                prop_assert_eq!(block.matter, chunk.get_block(c).matter);
+            }
+        }
+
+        #[test]
+        fn write_to_round_trips(edits in prop::collection::vec(((0..32i64, 0..32i64, 0..32i64), any::<BlockId>()), 0..200)) {
+            let chunk = edited_chunk(&edits);
+            let mut data = Vec::new();
+            chunk.write_to(&mut data).unwrap();
+            assert_same_blocks(&chunk, &Chunk::load(Coords(0, 0, 0), &data).unwrap());
+        }
+
+        #[test]
+        fn store_round_trips(edits in prop::collection::vec(((0..32i64, 0..32i64, 0..32i64), any::<BlockId>()), 0..200)) {
+            let chunk = edited_chunk(&edits);
+            let mut data = Vec::new();
+            chunk.store(&mut data).unwrap();
+            assert_same_blocks(&chunk, &Chunk::load(Coords(0, 0, 0), &data).unwrap());
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use super::block::{Block, BlockId};
+use super::chunk;
+use super::coords::Coords;
+use super::palette::Blocks;
+use super::worldgen::Worldgen;
+use std::convert::TryFrom;
+use std::io;
+
+/// Makes up the blocks of chunks that were never saved. Chunks are given by
+/// the coordinates of their first block.
+pub trait WorldGenerator {
+    fn generate_chunk(&self, c: Coords) -> Blocks;
+    /// Whether `generate_chunk(c)` would be all air.
+    fn is_air_chunk(&self, c: Coords) -> bool {
+        (0..32).all(|x| (0..32).all(|z| self.height_at(c.0 + x, c.2 + z) < c.1))
+    }
+    /// Height of the highest block in column `x`, `z` that may not be air.
+    fn height_at(&self, x: i64, z: i64) -> i64;
+}
+
+/// The generator called `name`, set up with `settings` as saved in the level.
+pub fn open(name: &str, settings: &toml::value::Table, seed: u32) -> io::Result<Box<dyn WorldGenerator + Send + Sync>> {
+    Ok(match name {
+        "noise" => Box::new(Worldgen::new(seed)),
+        "superflat" => Box::new(Superflat::from_settings(settings)?),
+        "void" => Box::new(Void),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown world generator {:?}", name))),
+    })
+}
+
+/// Flat layers of blocks, the first at height 0, with air above.
+pub struct Superflat {
+    layers: Vec<BlockId>,
+}
+
+impl Superflat {
+    pub fn new(layers: Vec<BlockId>) -> Superflat {
+        Superflat { layers }
+    }
+    fn from_settings(settings: &toml::value::Table) -> io::Result<Superflat> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "superflat layers must be a list of block ids");
+        let layers = match settings.get("layers") {
+            Some(toml::Value::Array(layers)) => layers,
+            _ => return Err(invalid()),
+        };
+        let layers = layers.iter()
+            .map(|layer| layer.as_integer().and_then(|id| BlockId::try_from(id).ok()).ok_or_else(invalid))
+            .collect::<io::Result<_>>()?;
+        Ok(Superflat::new(layers))
+    }
+}
+
+impl WorldGenerator for Superflat {
+    fn generate_chunk(&self, c: Coords) -> Blocks {
+        if self.is_air_chunk(c) {
+            return Blocks::Single(Block::AIR);
+        }
+        Blocks::from_fn(|i| {
+            let y = c.1 + chunk::block_coords(i).1;
+            match usize::try_from(y).ok().and_then(|y| self.layers.get(y)) {
+                Some(&id) => Block::new(id),
+                None => Block::AIR,
+            }
+        })
+    }
+    fn height_at(&self, _x: i64, _z: i64) -> i64 {
+        self.layers.len() as i64 - 1
+    }
+}
+
+/// Nothing but air.
+pub struct Void;
+
+impl WorldGenerator for Void {
+    fn generate_chunk(&self, _c: Coords) -> Blocks {
+        Blocks::Single(Block::AIR)
+    }
+    fn height_at(&self, _x: i64, _z: i64) -> i64 {
+        i64::MIN
+    }
+}
@@ -0,0 +1,81 @@
+use byteorder::{ByteOrder, NetworkEndian};
+use super::block::{Block, BlockId};
+use super::coords::Coords;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/* x, y and z as i64, the block id, then a CRC32 of all that */
+const RECORD: usize = 8 * 3 + 1 + 4;
+
+/// Append-only log of the block edits made since the last save, so that
+/// they can be redone if the server dies before saving again.
+pub struct Journal {
+    file: File,
+    path: PathBuf,
+}
+
+fn open_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().read(true).append(true).create(true).open(path)
+}
+
+impl Journal {
+    pub fn open(path: &Path) -> io::Result<Journal> {
+        Ok(Journal {
+            file: open_file(path)?,
+            path: path.to_path_buf(),
+        })
+    }
+    /// Edits recorded so far. A partly written last record, left by a crash
+    /// in the middle of an edit, is dropped.
+    pub fn replay(&mut self) -> io::Result<Vec<(Coords, Block)>> {
+        let mut data = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut data)?;
+        let mut edits = Vec::new();
+        for record in data.chunks_exact(RECORD) {
+            if crc32fast::hash(&record[..RECORD - 4]) != NetworkEndian::read_u32(&record[RECORD - 4..]) {
+                break;
+            }
+            let c = Coords(
+                NetworkEndian::read_i64(&record[0..8]),
+                NetworkEndian::read_i64(&record[8..16]),
+                NetworkEndian::read_i64(&record[16..24]),
+            );
+            edits.push((c, Block::new(record[24] as BlockId)));
+        }
+        let valid = (edits.len() * RECORD) as u64;
+        if valid < data.len() as u64 {
+            println!("Dropping {} bytes of damaged journal", data.len() as u64 - valid);
+            self.file.set_len(valid)?;
+        }
+        Ok(edits)
+    }
+    pub fn record(&mut self, c: Coords, block: Block) -> io::Result<()> {
+        let mut record = [0; RECORD];
+        NetworkEndian::write_i64(&mut record[0..8], c.0);
+        NetworkEndian::write_i64(&mut record[8..16], c.1);
+        NetworkEndian::write_i64(&mut record[16..24], c.2);
+        record[24] = block.matter;
+        let crc = crc32fast::hash(&record[..RECORD - 4]);
+        NetworkEndian::write_u32(&mut record[RECORD - 4..], crc);
+        self.file.write_all(&record)
+    }
+    /// Position to pass to `discard` once everything recorded so far is saved.
+    pub fn checkpoint(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+    /// Forgets the edits recorded before `checkpoint`, keeping later ones.
+    pub fn discard(&mut self, checkpoint: u64) -> io::Result<()> {
+        let mut rest = Vec::new();
+        self.file.seek(SeekFrom::Start(checkpoint))?;
+        self.file.read_to_end(&mut rest)?;
+        if rest.is_empty() {
+            self.file.set_len(0)?;
+            return self.file.sync_all();
+        }
+        super::storage::write_atomic(&self.path, &rest)?;
+        self.file = open_file(&self.path)?;
+        Ok(())
+    }
+}
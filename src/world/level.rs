@@ -0,0 +1,89 @@
+use byteorder::{ByteOrder, NetworkEndian};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/* bumped whenever a change to Level can't be read by older servers */
+const FORMAT_VERSION: u32 = 1;
+
+/// Rules changing how the game plays in a world.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GameRules {
+    /// Whether the time of day advances.
+    pub daylight_cycle: bool,
+}
+
+impl Default for GameRules {
+    fn default() -> GameRules {
+        GameRules {
+            daylight_cycle: true,
+        }
+    }
+}
+
+/// Everything about a world that isn't in its chunks, saved as TOML.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Level {
+    pub version: u32,
+    pub name: String,
+    pub seed: u32,
+    pub generator: String,
+    /// Where players appear; a height of 0 puts them on the ground.
+    pub spawn: [f64; 3],
+    /// Time of day, in ticks.
+    pub daytime: u32,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    /* tables go last, or they can't be written as TOML */
+    pub generator_settings: toml::value::Table,
+    #[serde(default)]
+    pub game_rules: GameRules,
+}
+
+impl Level {
+    pub fn new(name: &str, seed: u32, generator: &str) -> Level {
+        Level {
+            version: FORMAT_VERSION,
+            name: name.to_string(),
+            seed,
+            generator: generator.to_string(),
+            spawn: [0.0, 0.0, 0.0],
+            daytime: 0,
+            created: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            generator_settings: toml::value::Table::new(),
+            game_rules: GameRules::default(),
+        }
+    }
+    /// Reads a level saved by `encode`, or by older versions as the time of
+    /// day followed by the seed, if it was saved at all. Worlds saved without
+    /// a seed were all generated with seed 0; `name` fills in the name.
+    pub fn decode(data: &[u8], name: &str) -> io::Result<Level> {
+        if data.len() <= 8 {
+            if data.len() < 4 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "level data is cut short"));
+            }
+            let seed = if data.len() >= 8 { NetworkEndian::read_u32(&data[4..8]) } else { 0 };
+            let mut level = Level::new(name, seed, "noise");
+            level.daytime = NetworkEndian::read_u32(data);
+            /* it is older than this, but nobody knows by how much */
+            level.created = 0;
+            return Ok(level);
+        }
+        let text = std::str::from_utf8(data)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "level data is not UTF-8"))?;
+        let mut level: Level = toml::from_str(text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("level data is invalid: {}", e)))?;
+        if level.version > FORMAT_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("level format version {} is newer than this server's {}", level.version, FORMAT_VERSION)));
+        }
+        /* saved again in this version's format */
+        level.version = FORMAT_VERSION;
+        Ok(level)
+    }
+    pub fn encode(&self) -> Vec<u8> {
+        /* every field has a TOML equivalent */
+        toml::to_string(self).unwrap().into_bytes()
+    }
+}
@@ -0,0 +1,111 @@
+use super::chunk::Chunk;
+use super::coords::Coords;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+/* chunks waiting for a worker, keyed by distance and then by their tags,
+ * which go up with each request */
+struct Queue {
+    jobs: BTreeMap<(i64, u64), Coords>,
+    stopping: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+impl Shared {
+    /* waits for the closest chunk to load and its tag, or None once stopping */
+    fn next_job(&self) -> Option<(Coords, u64)> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if queue.stopping {
+                return None;
+            }
+            if let Some(((_, tag), cc)) = queue.jobs.pop_first() {
+                return Some((cc, tag));
+            }
+            queue = self.ready.wait(queue).unwrap();
+        }
+    }
+}
+
+/// Loads or generates chunks on a pool of worker threads, those closest to
+/// the players that asked for them first.
+pub struct Loader {
+    shared: Arc<Shared>,
+    capacity: usize,
+    done: Receiver<(Coords, u64, io::Result<Chunk>)>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Loader {
+    /// Starts `threads` workers, each getting chunks with `load`. At most
+    /// `capacity` chunks wait for a worker at a time.
+    pub fn new<F>(threads: usize, capacity: usize, load: F) -> Loader
+        where F: Fn(Coords) -> io::Result<Chunk> + Send + Sync + 'static
+    {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                jobs: BTreeMap::new(),
+                stopping: false,
+            }),
+            ready: Condvar::new(),
+        });
+        let load = Arc::new(load);
+        let (done_tx, done) = mpsc::channel();
+        let threads = (0..threads).map(|i| {
+            let shared = Arc::clone(&shared);
+            let load = Arc::clone(&load);
+            let done_tx = done_tx.clone();
+            thread::Builder::new().name(format!("loader {}", i)).spawn(move || {
+                while let Some((cc, tag)) = shared.next_job() {
+                    if done_tx.send((cc, tag, load(cc))).is_err() {
+                        break;
+                    }
+                }
+            }).expect("could not start a chunk loader thread")
+        }).collect();
+        Loader {
+            shared,
+            capacity,
+            done,
+            threads,
+        }
+    }
+    /// Queues chunk `cc`, `distance` away from the player that wants it.
+    /// `tag` tells this request apart from others for the same chunk, and
+    /// must be higher than those of earlier requests. If the queue is full,
+    /// the farthest chunk in it is pushed out and returned with its tag;
+    /// that may be `cc` itself.
+    pub fn request(&self, cc: Coords, tag: u64, distance: i64) -> Option<(Coords, u64)> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.jobs.insert((distance, tag), cc);
+        let dropped = if queue.jobs.len() > self.capacity {
+            queue.jobs.pop_last().map(|((_, tag), cc)| (cc, tag))
+        } else {
+            None
+        };
+        self.shared.ready.notify_one();
+        dropped
+    }
+    /// A chunk that has finished loading, if any has, with the tag it was
+    /// requested with.
+    pub fn try_finish(&self) -> Option<(Coords, u64, io::Result<Chunk>)> {
+        self.done.try_recv().ok()
+    }
+}
+
+impl Drop for Loader {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().stopping = true;
+        self.shared.ready.notify_all();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
@@ -1,89 +1,510 @@
-use byteorder::{ReadBytesExt, WriteBytesExt, NetworkEndian};
+use fs2::FileExt;
 use super::block::Block;
-use super::chunk::Chunk;
+use super::chunk::{self, Chunk};
 use super::coords::Coords;
-use super::worldgen::Worldgen;
+use super::generator::{self, WorldGenerator};
+use super::journal::Journal;
+use super::level::Level;
+use super::loader::Loader;
+use super::saver::{Saver, SharedStorage, Snapshot};
+use super::storage::{self, WorldStorage};
+use crate::clock::TICKS_PER_SECOND;
+use crate::config::Config;
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::BufReader;
+use std::hash::{BuildHasher, Hasher};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/* what is needed to finish the save in progress */
+struct PendingSave {
+    chunks: Vec<Coords>,
+    journal_checkpoint: u64,
+}
 
 pub struct Map {
     chunks: HashMap<Coords, Chunk>,
+    /* tick each loaded chunk was last used at */
+    last_used: HashMap<Coords, u64>,
+    ticks: u64,
+    storage: SharedStorage,
+    saver: Saver,
+    saving: Option<PendingSave>,
+    loader: Loader,
+    /* chunks waiting to be loaded, with the tag of the request that will
+     * load them; results with any other tag are outdated */
+    requested: HashMap<Coords, u64>,
+    next_tag: u64,
+    /* requests answered since poll_chunks was last called */
+    answered: Vec<(Coords, io::Result<()>)>,
     daytime: usize,
-    worldgen: Worldgen,
+    day_length: usize,
+    level: Level,
+    worldgen: Arc<dyn WorldGenerator + Send + Sync>,
+    quarantine: PathBuf,
+    journal: Journal,
+    /* held for as long as the map is open */
+    _lock: File,
+}
+
+/* coordinates of a chunk saved by older versions, one chunk per file */
+fn parse_chunk_file_name(name: &str) -> Option<Coords> {
+    let mut fields = name.strip_prefix("chunk.")?.strip_suffix(".cf")?.split('.');
+    let c = Coords(fields.next()?.parse().ok()?, fields.next()?.parse().ok()?, fields.next()?.parse().ok()?);
+    match fields.next() {
+        None => Some(c),
+        Some(_) => None,
+    }
+}
+
+/* whether a file is part of a world as older versions kept it */
+fn is_legacy_file(name: &str) -> bool {
+    name == "level.lf" || parse_chunk_file_name(name).is_some()
+}
+
+/* moves a world that older versions kept in the current directory into dir,
+ * unless dir already holds a world of its own */
+fn migrate_legacy_world(dir: &Path) -> io::Result<()> {
+    let mut legacy = Vec::new();
+    for entry in fs::read_dir(".")? {
+        let entry = entry?;
+        if entry.file_name().to_str().is_some_and(is_legacy_file) {
+            legacy.push(entry.file_name());
+        }
+    }
+    if legacy.is_empty() {
+        return Ok(());
+    }
+    if dir.exists() {
+        if fs::canonicalize(dir)? == std::env::current_dir()? {
+            return Ok(());
+        }
+        return Err(io::Error::other(format!(
+            "the current directory has a world from an older version, but {} exists already; \
+             move level.lf and the chunk.*.cf files into a world directory of their own", dir.display())));
+    }
+    fs::create_dir_all(dir)?;
+    for name in &legacy {
+        fs::rename(name, dir.join(name))?;
+    }
+    println!("Moved the world in the current directory ({} files) into {}", legacy.len(), dir.display());
+    Ok(())
+}
+
+/* moves chunk.p.q.r.cf files into the world storage */
+fn migrate_chunk_files(dir: &Path, storage: &mut dyn WorldStorage) -> io::Result<()> {
+    let mut migrated = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let cc = match path.file_name().and_then(|n| n.to_str()).and_then(parse_chunk_file_name) {
+            Some(cc) => cc,
+            None => continue,
+        };
+        let data = fs::read(&path)?;
+        storage.store_chunk(cc, &data)?;
+        migrated.push(path);
+    }
+    if !migrated.is_empty() {
+        storage.sync()?;
+        for path in &migrated {
+            fs::remove_file(path)?;
+        }
+        println!("Moved {} chunk files into the world storage", migrated.len());
+    }
+    Ok(())
+}
+
+/* moves a chunk that can't be loaded out of the way, where it can be looked
+ * at; without data, as when it can't even be read, it is only removed */
+fn quarantine_chunk(storage: &mut dyn WorldStorage, quarantine: &Path, cc: Coords, data: Option<&[u8]>, err: &dyn fmt::Display) -> io::Result<()> {
+    match data {
+        Some(data) => {
+            let Coords(p, q, r) = cc;
+            let path = quarantine.join(format!("chunk.{}.{}.{}.cf", p, q, r));
+            println!("Chunk {:?} is damaged ({}), moving it to {}", cc, err, path.display());
+            fs::create_dir_all(quarantine)?;
+            storage::write_atomic(&path, data)?;
+        }
+        None => println!("Chunk {:?} can't be read ({}), removing it", cc, err),
+    }
+    storage.remove_chunk(cc)
+}
+
+/* a seed for a new world, when none is configured */
+fn random_seed() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
+
+/* the saved chunk cc, or a newly generated one if it was never saved */
+fn load_chunk(storage: &SharedStorage, worldgen: &dyn WorldGenerator, quarantine: &Path, cc: Coords) -> io::Result<Chunk> {
+    let mut storage = storage.lock().unwrap();
+    let data = match storage.load_chunk(cc)? {
+        Some(data) => data,
+        None => {
+            drop(storage);
+            return Ok(Chunk::new(worldgen, cc));
+        }
+    };
+    println!("Loading chunk {:?}", cc);
+    match Chunk::load(cc, &data) {
+        Ok(chunk) => Ok(chunk),
+        Err(e) => {
+            /* made durable by the next save; syncing here could commit half of
+             * one that is under way */
+            quarantine_chunk(&mut **storage, quarantine, cc, Some(&data), &e)?;
+            drop(storage);
+            Ok(Chunk::new(worldgen, cc))
+        }
+    }
+}
+
+/* makes sure every saved chunk can be loaded, upgrading old ones */
+fn check_chunks(storage: &mut dyn WorldStorage, quarantine: &Path) -> io::Result<()> {
+    let chunks = storage.list_chunks()?;
+    let mut damaged = 0;
+    let mut upgraded = 0;
+    for &cc in &chunks {
+        let data = match storage.load_chunk(cc) {
+            Ok(Some(data)) => data,
+            Ok(None) => continue,
+            Err(e) => {
+                quarantine_chunk(storage, quarantine, cc, None, &e)?;
+                damaged += 1;
+                continue;
+            }
+        };
+        match Chunk::load(cc, &data) {
+            Err(e) => {
+                quarantine_chunk(storage, quarantine, cc, Some(&data), &e)?;
+                damaged += 1;
+            }
+            Ok(chunk) if chunk::is_headerless(&data) => {
+                let mut new_data = Vec::new();
+                chunk.store(&mut new_data)?;
+                storage.store_chunk(cc, &new_data)?;
+                upgraded += 1;
+            }
+            Ok(_) => (),
+        }
+    }
+    storage.sync()?;
+    println!("World has {} saved chunks, {} of them damaged", chunks.len(), damaged);
+    if upgraded > 0 {
+        println!("Upgraded {} chunks to the current format", upgraded);
+    }
+    Ok(())
 }
 
 impl Map {
-    pub fn new() -> Map {
-        let time = match File::open("level.lf") {
-            Err(_) => 0,
-            Ok(mut f) => {
-                f.read_u32::<NetworkEndian>().unwrap()
+    /// Opens the world configured in `config`, creating it if needed. Fails
+    /// if another server has the same world open.
+    pub fn new(config: &Config) -> io::Result<Map> {
+        let dir = config.world.clone();
+        let seed = config.seed.as_ref().map(|s| s.value());
+        let day_length = config.day_length * TICKS_PER_SECOND as usize;
+        migrate_legacy_world(&dir)?;
+        fs::create_dir_all(&dir)?;
+        let lock = OpenOptions::new().create(true).truncate(false).write(true).open(dir.join("session.lock"))?;
+        if let Err(e) = lock.try_lock_exclusive() {
+            if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() {
+                return Err(io::Error::other("already in use by another server"));
+            }
+            return Err(e);
+        }
+        let mut storage = storage::open(config.storage, &dir)?;
+        let quarantine = dir.join(storage::QUARANTINE_DIR);
+        migrate_chunk_files(&dir, &mut *storage)?;
+        check_chunks(&mut *storage, &quarantine)?;
+        let name = dir.file_name().map_or_else(|| "world".to_string(), |n| n.to_string_lossy().into_owned());
+        /* level.lf is where older versions kept it, whatever the storage */
+        let legacy_level = dir.join("level.lf");
+        let data = match storage.load_level()? {
+            Some(data) => Some(data),
+            None => match fs::read(&legacy_level) {
+                Ok(data) => Some(data),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            },
+        };
+        let level = match data {
+            Some(data) => {
+                let level = Level::decode(&data, &name)?;
+                if seed.is_some_and(|seed| seed != level.seed) {
+                    println!("Ignoring the configured seed, the world already has seed {}", level.seed);
+                }
+                level
             }
+            None => {
+                let mut level = Level::new(&name, seed.unwrap_or_else(random_seed), config.generator.name());
+                level.generator_settings = config.generator_settings();
+                level
+            }
+        };
+        /* in the current format, and before any chunk is saved */
+        storage.store_level(&level.encode())?;
+        storage.sync()?;
+        match fs::remove_file(&legacy_level) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            result => result?,
+        }
+        println!("World {:?} has seed {} and generator {}", level.name, level.seed, level.generator);
+        let journal = Journal::open(&dir.join("edits.journal"))?;
+        let storage = Arc::new(Mutex::new(storage));
+        let worldgen: Arc<dyn WorldGenerator + Send + Sync> =
+            Arc::from(generator::open(&level.generator, &level.generator_settings, level.seed)?);
+        let loader = {
+            let (storage, worldgen, quarantine) = (Arc::clone(&storage), Arc::clone(&worldgen), quarantine.clone());
+            Loader::new(config.loader_threads, config.loader_queue, move |cc| load_chunk(&storage, &*worldgen, &quarantine, cc))
         };
-        Map {
+        let mut map = Map {
             chunks: HashMap::new(),
-            daytime: time as usize,
-            worldgen: Worldgen::new(0),
+            last_used: HashMap::new(),
+            ticks: 0,
+            saver: Saver::new(Arc::clone(&storage)),
+            storage,
+            saving: None,
+            daytime: level.daytime as usize % day_length,
+            day_length,
+            level,
+            loader,
+            requested: HashMap::new(),
+            next_tag: 0,
+            answered: Vec::new(),
+            worldgen,
+            quarantine,
+            journal,
+            _lock: lock,
+        };
+        let edits = map.journal.replay()?;
+        if !edits.is_empty() {
+            println!("Redoing {} block edits from the journal", edits.len());
+            for (c, block) in edits {
+                map.get_mut_chunk(c.chunk())?.replace_block(c.in_chunk(), block);
+            }
+            map.save()?;
+            map.wait_for_save()?;
         }
+        Ok(map)
     }
-    pub fn get_mut_chunk(&mut self, cc: Coords) -> &mut Chunk {
-        let worldgen = &self.worldgen;
-        self.chunks.entry(cc).or_insert_with(|| {
-            let Coords(p, q, r) = cc;
-            match File::open(format!("chunk.{}.{}.{}.cf", p, q, r)) {
-                Err(_) => Chunk::new(worldgen, cc),
-                Ok(f) => {
-                    println!("Trying file chunk.{}.{}.{}.cf", p, q, r);
-                    println!("Loading chunk {:?}", cc);
-                    Chunk::load(cc, BufReader::new(f))
+    /* chunk cc, loading it right away if it isn't yet; this blocks, so it is
+     * only for opening the map */
+    fn get_mut_chunk(&mut self, cc: Coords) -> io::Result<&mut Chunk> {
+        if !self.chunks.contains_key(&cc) {
+            let chunk = load_chunk(&self.storage, &*self.worldgen, &self.quarantine, cc)?;
+            self.chunks.insert(cc, chunk);
+            /* what the loader gets would be outdated by now */
+            if self.requested.remove(&cc).is_some() {
+                self.answered.push((cc, Ok(())));
+            }
+        }
+        self.last_used.insert(cc, self.ticks);
+        Ok(self.chunks.get_mut(&cc).unwrap())
+    }
+    /// Chunk `cc` if it is loaded; otherwise it is queued to be loaded,
+    /// `distance` being how far it is from the player that wants it.
+    /// `poll_chunks` tells when it is.
+    pub fn request_chunk(&mut self, cc: Coords, distance: i64) -> Option<&Chunk> {
+        if self.chunks.contains_key(&cc) {
+            self.last_used.insert(cc, self.ticks);
+            return self.chunks.get(&cc);
+        }
+        if !self.requested.contains_key(&cc) {
+            let tag = self.next_tag;
+            self.next_tag += 1;
+            self.requested.insert(cc, tag);
+            if let Some((dropped, dropped_tag)) = self.loader.request(cc, tag, distance) {
+                if self.requested.get(&dropped) == Some(&dropped_tag) {
+                    println!("Too many chunks to load, refusing {:?}", dropped);
+                    self.requested.remove(&dropped);
+                    self.answered.push((dropped, Err(io::Error::other("too many chunks are waiting to be loaded"))));
                 }
             }
-        })
+        }
+        None
     }
-    pub fn get_chunk(&mut self, cc: Coords) -> &Chunk {
-        self.get_mut_chunk(cc)
+    /// Requested chunks that were loaded, or that failed to, since this was
+    /// last called. A chunk may be unloaded again before it is asked for.
+    pub fn poll_chunks(&mut self) -> Vec<(Coords, io::Result<()>)> {
+        while let Some((cc, tag, result)) = self.loader.try_finish() {
+            if self.requested.get(&cc) != Some(&tag) {
+                continue;
+            }
+            self.requested.remove(&cc);
+            let result = match result {
+                Ok(chunk) => {
+                    self.chunks.insert(cc, chunk);
+                    self.last_used.insert(cc, self.ticks);
+                    Ok(())
+                }
+                Err(e) => {
+                    println!("Error while loading chunk {:?}: {}", cc, e);
+                    Err(e)
+                }
+            };
+            self.answered.push((cc, result));
+        }
+        mem::take(&mut self.answered)
     }
-    pub fn replace_block(&mut self, c: Coords, block: Block) {
-        let chunk = self.get_mut_chunk(c.chunk());
-        chunk.replace_block(c.in_chunk(), block);
+    pub fn seed(&self) -> u32 {
+        self.level.seed
+    }
+    /// Where players appear; a height of 0 puts them on the ground.
+    pub fn spawn(&self) -> [f64; 3] {
+        self.level.spawn
     }
-    pub fn get_block(&mut self, c: Coords) -> Block {
-        let chunk = self.get_chunk(c.chunk());
-        chunk.get_block(c.in_chunk())
+    /// Chunk `cc`, if it is loaded.
+    pub fn loaded_chunk(&self, cc: Coords) -> Option<&Chunk> {
+        self.chunks.get(&cc)
+    }
+    /// Changes block `c`, if its chunk is loaded.
+    pub fn replace_block(&mut self, c: Coords, block: Block) -> bool {
+        let chunk = match self.chunks.get_mut(&c.chunk()) {
+            Some(chunk) => chunk,
+            None => return false,
+        };
+        if let Err(e) = self.journal.record(c, block) {
+            println!("Error while writing to the journal: {}", e);
+        }
+        chunk.replace_block(c.in_chunk(), block);
+        self.last_used.insert(c.chunk(), self.ticks);
+        true
     }
     pub fn get_time(&self) -> usize {
         self.daytime
     }
     pub fn tick(&mut self, nticks: usize) {
-        self.daytime = (self.daytime + nticks) % 12000;
-        println!("daytime = {}", self.daytime);
+        if self.level.game_rules.daylight_cycle {
+            self.daytime = (self.daytime + nticks) % self.day_length;
+        }
+        self.ticks += nticks as u64;
+    }
+    /// Whether any chunk changed since it was last saved.
+    pub fn has_changes(&self) -> bool {
+        self.chunks.values().any(|chunk| !chunk.is_unchanged())
     }
-    pub fn save(&mut self) {
+    /// Unloads the chunks that are more than `view_distance` chunks away from
+    /// every player, then the least recently used ones while more than
+    /// `max_chunks` are loaded. Changed chunks must be saved before they can
+    /// be unloaded; returns whether any are waiting for that.
+    pub fn unload(&mut self, players: &[Coords], view_distance: i64, max_chunks: usize) -> bool {
+        /* if the save fails, the chunks being saved must still be here */
+        if self.saving.is_some() {
+            return false;
+        }
+        let in_view = |cc: &Coords| players.iter().any(|p| {
+            (cc.0 - p.0).abs() <= view_distance
+                && (cc.1 - p.1).abs() <= view_distance
+                && (cc.2 - p.2).abs() <= view_distance
+        });
+        let mut waiting = false;
+        let loaded = self.chunks.len();
+        let last_used = &mut self.last_used;
         self.chunks.retain(|cc, chunk| {
-            let Coords(p, q, r) = cc;
-            if !chunk.is_unchanged() {
-                println!("Creating file chunk.{}.{}.{}.cf", p, q, r);
-                let mut cf = File::create(format!("chunk.{}.{}.{}.cf", p, q, r)).unwrap();
-                println!("Writing chunk ({}, {}, {})", p, q, r);
-                chunk.write_to(&mut cf).unwrap();
+            if in_view(cc) {
+                true
+            } else if !chunk.is_unchanged() {
+                waiting = true;
                 true
             } else {
+                last_used.remove(cc);
                 false
             }
         });
-        {
-            let mut lf = File::create("level.lf").unwrap();
-            println!("Writing time");
-            lf.write_u32::<NetworkEndian>(self.daytime as u32).unwrap();
+        if self.chunks.len() > max_chunks {
+            let mut lru: Vec<(u64, Coords)> = self.chunks.iter()
+                .filter(|(_, chunk)| chunk.is_unchanged())
+                .map(|(cc, _)| (self.last_used[cc], *cc))
+                .collect();
+            lru.sort_unstable_by_key(|&(used, _)| used);
+            for (_, cc) in lru.into_iter().take(self.chunks.len() - max_chunks) {
+                self.chunks.remove(&cc);
+                self.last_used.remove(&cc);
+            }
+            waiting |= self.chunks.len() > max_chunks;
+        }
+        if self.chunks.len() < loaded {
+            println!("Unloaded {} chunks", loaded - self.chunks.len());
+        }
+        waiting
+    }
+    /// Starts saving every changed chunk in the background.
+    pub fn save(&mut self) -> io::Result<()> {
+        /* one save at a time; if the last one failed, this one retries it */
+        if let Err(e) = self.wait_for_save() {
+            println!("Error while saving: {}", e);
+        }
+        let mut snapshot = Snapshot {
+            chunks: Vec::new(),
+            level: Level {
+                daytime: self.daytime as u32,
+                ..self.level.clone()
+            }.encode(),
+        };
+        for (&cc, chunk) in self.chunks.iter().filter(|(_, chunk)| !chunk.is_unchanged()) {
+            println!("Writing chunk {:?}", cc);
+            snapshot.chunks.push((cc, chunk.clone()));
+        }
+        let pending = PendingSave {
+            chunks: snapshot.chunks.iter().map(|&(cc, _)| cc).collect(),
+            journal_checkpoint: self.journal.checkpoint()?,
+        };
+        self.saver.start(snapshot)?;
+        /* only once the save is under way, or a failure to start it would
+         * leave them looking saved, free to be unloaded */
+        for cc in &pending.chunks {
+            self.chunks.get_mut(cc).unwrap().mark_unchanged();
+        }
+        self.saving = Some(pending);
+        Ok(())
+    }
+    /// Finishes the save in progress, if it is done.
+    pub fn poll_save(&mut self) -> io::Result<()> {
+        if self.saving.is_none() {
+            return Ok(());
+        }
+        match self.saver.try_finish() {
+            Some(result) => self.finish_save(result),
+            None => Ok(()),
+        }
+    }
+    /// Waits for the save in progress, if any, to be done.
+    pub fn wait_for_save(&mut self) -> io::Result<()> {
+        if self.saving.is_none() {
+            return Ok(());
+        }
+        let result = self.saver.finish();
+        self.finish_save(result)
+    }
+    fn finish_save(&mut self, result: io::Result<()>) -> io::Result<()> {
+        let pending = self.saving.take().unwrap();
+        match result {
+            Ok(()) => {
+                self.journal.discard(pending.journal_checkpoint)?;
+                println!("Saved!");
+                Ok(())
+            }
+            Err(e) => {
+                /* they are still loaded, since only unchanged chunks get unloaded */
+                for cc in pending.chunks {
+                    if let Some(chunk) = self.chunks.get_mut(&cc) {
+                        chunk.mark_changed();
+                    }
+                }
+                Err(e)
+            }
         }
-        println!("Saved!");
     }
 }
 
 impl Drop for Map {
     fn drop(&mut self) {
-        self.save();
+        if let Err(e) = self.save().and_then(|_| self.wait_for_save()) {
+            println!("Error while saving: {}", e);
+        }
     }
 }
 
@@ -0,0 +1,81 @@
+use super::block::{Block, BlockId};
+
+const BLOCKS: usize = 32*32*32;
+
+/// The blocks of a chunk. A chunk made of a single kind of block stores just
+/// that block; any other stores, for each block, its index in a list of the
+/// kinds of block in the chunk, packed in as few bits as that list needs.
+#[derive(Clone)]
+pub enum Blocks {
+    Single(Block),
+    Packed {
+        palette: Vec<BlockId>,
+        /* bits per block; always divides 64, so no block straddles two words */
+        bits: usize,
+        words: Vec<u64>,
+    },
+}
+
+impl Blocks {
+    /// Blocks `0..32*32*32`, `f(i)` being block `i`.
+    pub fn from_fn<F: FnMut(usize) -> Block>(mut f: F) -> Blocks {
+        let mut blocks = Blocks::Single(f(0));
+        for i in 1..BLOCKS {
+            blocks.set(i, f(i));
+        }
+        blocks
+    }
+    pub fn get(&self, i: usize) -> Block {
+        match self {
+            Blocks::Single(b) => *b,
+            Blocks::Packed { palette, bits, words } => {
+                let per_word = 64 / bits;
+                let entry = (words[i / per_word] >> (i % per_word * bits)) & ((1 << bits) - 1);
+                Block::new(palette[entry as usize])
+            }
+        }
+    }
+    pub fn set(&mut self, i: usize, block: Block) {
+        if let Blocks::Single(b) = *self {
+            if b.matter == block.matter {
+                return;
+            }
+            *self = Blocks::Packed {
+                palette: vec![b.matter],
+                bits: 1,
+                words: vec![0; BLOCKS / 64],
+            };
+        }
+        if let Blocks::Packed { palette, bits, words } = self {
+            let entry = match palette.iter().position(|&id| id == block.matter) {
+                Some(entry) => entry,
+                None => {
+                    palette.push(block.matter);
+                    if palette.len() > 1 << *bits {
+                        *words = repack(words, *bits, *bits * 2);
+                        *bits *= 2;
+                    }
+                    palette.len() - 1
+                }
+            };
+            let per_word = 64 / *bits;
+            let shift = i % per_word * *bits;
+            let word = &mut words[i / per_word];
+            *word = (*word & !(((1 << *bits) - 1) << shift)) | ((entry as u64) << shift);
+        }
+    }
+    pub fn iter(&self) -> impl Iterator<Item = Block> + '_ {
+        (0..BLOCKS).map(move |i| self.get(i))
+    }
+}
+
+/* the same entries, packed in more bits each */
+fn repack(words: &[u64], bits: usize, new_bits: usize) -> Vec<u64> {
+    let (per_word, new_per_word) = (64 / bits, 64 / new_bits);
+    let mut new_words = vec![0; BLOCKS / new_per_word];
+    for i in 0..BLOCKS {
+        let entry = (words[i / per_word] >> (i % per_word * bits)) & ((1 << bits) - 1);
+        new_words[i / new_per_word] |= entry << (i % new_per_word * new_bits);
+    }
+    new_words
+}
@@ -0,0 +1,301 @@
+use byteorder::{ByteOrder, NetworkEndian};
+use super::coords::Coords;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/* chunks per region along each axis */
+pub const REGION_SIZE: i64 = 16;
+const REGION_CHUNKS: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+const SECTOR: u64 = 4096;
+/* one (first sector, length in bytes) pair per chunk */
+const ENTRY: usize = 8;
+const HEADER_SECTORS: u32 = (REGION_CHUNKS * ENTRY) as u32 / SECTOR as u32;
+
+/// Region holding the chunk at chunk coordinates `cc`.
+pub fn region_of(cc: Coords) -> Coords {
+    Coords(cc.0.div_euclid(REGION_SIZE), cc.1.div_euclid(REGION_SIZE), cc.2.div_euclid(REGION_SIZE))
+}
+
+fn index_in_region(cc: Coords) -> usize {
+    let (x, y, z) = (cc.0.rem_euclid(REGION_SIZE), cc.1.rem_euclid(REGION_SIZE), cc.2.rem_euclid(REGION_SIZE));
+    (x + y * REGION_SIZE + z * REGION_SIZE * REGION_SIZE) as usize
+}
+
+fn chunk_at(rc: Coords, index: usize) -> Coords {
+    let i = index as i64;
+    Coords(
+        rc.0 * REGION_SIZE + i % REGION_SIZE,
+        rc.1 * REGION_SIZE + (i / REGION_SIZE) % REGION_SIZE,
+        rc.2 * REGION_SIZE + i / (REGION_SIZE * REGION_SIZE),
+    )
+}
+
+fn sectors_for(len: u32) -> u32 {
+    len.div_ceil(SECTOR as u32)
+}
+
+/// A file holding the compressed data of up to 16×16×16 chunks. It starts
+/// with a table giving, for each chunk, the sector its data starts at and its
+/// length; chunks not in the region have a length of 0. Each chunk takes a
+/// contiguous run of 4 KiB sectors, reused as chunks are rewritten.
+pub struct Region {
+    file: File,
+    table: Vec<(u32, u32)>,
+    used: Vec<bool>,
+    /* entries pointing into the header, past the end of the file or into
+     * another chunk's sectors; their sectors aren't theirs to free */
+    damaged: HashSet<usize>,
+}
+
+impl Region {
+    pub fn open(path: &Path) -> io::Result<Region> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let mut header = vec![0; REGION_CHUNKS * ENTRY];
+        let file_len = file.metadata()?.len();
+        if file_len == 0 {
+            file.write_all(&header)?;
+        } else if file_len < header.len() as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: header is cut short", path.display())));
+        } else {
+            file.read_exact(&mut header)?;
+        }
+        let mut region = Region {
+            file,
+            table: Vec::with_capacity(REGION_CHUNKS),
+            used: vec![true; HEADER_SECTORS as usize],
+            damaged: HashSet::new(),
+        };
+        for (index, entry) in header.chunks(ENTRY).enumerate() {
+            let sector = NetworkEndian::read_u32(&entry[0..4]);
+            let len = NetworkEndian::read_u32(&entry[4..8]);
+            if len != 0 {
+                /* in u64, as damaged entries can be anything */
+                let past_end = sector as u64 * SECTOR + len as u64 > file_len;
+                let (start, end) = (sector as usize, sector as usize + sectors_for(len) as usize);
+                let overlaps = region.used.get(start..end.min(region.used.len())).is_some_and(|s| s.contains(&true));
+                if sector < HEADER_SECTORS || past_end || overlaps {
+                    println!("{}: chunk {} has damaged sectors", path.display(), index);
+                    region.damaged.insert(index);
+                } else {
+                    region.mark(sector, sectors_for(len), true);
+                }
+            }
+            region.table.push((sector, len));
+        }
+        Ok(region)
+    }
+    fn mark(&mut self, start: u32, count: u32, used: bool) {
+        let end = (start + count) as usize;
+        if self.used.len() < end {
+            self.used.resize(end, false);
+        }
+        for s in &mut self.used[start as usize..end] {
+            *s = used;
+        }
+    }
+    /* first run of count free sectors, possibly past the end of the file */
+    fn allocate(&self, count: u32) -> u32 {
+        let mut run = 0;
+        for (i, &used) in self.used.iter().enumerate() {
+            if used {
+                run = 0;
+            } else {
+                run += 1;
+                if run == count {
+                    return i as u32 + 1 - count;
+                }
+            }
+        }
+        (self.used.len() - run as usize) as u32
+    }
+    /// Chunks present in this region, which is region `rc`.
+    pub fn chunks(&self, rc: Coords) -> impl Iterator<Item = Coords> + '_ {
+        self.table.iter().enumerate().filter(|(_, &(_, len))| len != 0).map(move |(i, _)| chunk_at(rc, i))
+    }
+    /// Compressed data of chunk `cc`, if the region has it.
+    pub fn read(&mut self, cc: Coords) -> io::Result<Option<Vec<u8>>> {
+        let index = index_in_region(cc);
+        let (sector, len) = self.table[index];
+        if len == 0 {
+            return Ok(None);
+        }
+        if self.damaged.contains(&index) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("sectors of chunk {:?} are damaged", cc)));
+        }
+        let mut data = vec![0; len as usize];
+        self.file.seek(SeekFrom::Start(sector as u64 * SECTOR))?;
+        self.file.read_exact(&mut data)?;
+        Ok(Some(data))
+    }
+    fn set_entry(&mut self, index: usize, sector: u32, len: u32) -> io::Result<()> {
+        let mut entry = [0; ENTRY];
+        NetworkEndian::write_u32(&mut entry[0..4], sector);
+        NetworkEndian::write_u32(&mut entry[4..8], len);
+        self.file.seek(SeekFrom::Start((index * ENTRY) as u64))?;
+        self.file.write_all(&entry)?;
+        self.table[index] = (sector, len);
+        Ok(())
+    }
+    /* frees the sectors of the chunk at index */
+    fn free(&mut self, index: usize) {
+        let (sector, len) = self.table[index];
+        if len != 0 && !self.damaged.remove(&index) {
+            self.mark(sector, sectors_for(len), false);
+        }
+    }
+    pub fn write(&mut self, cc: Coords, data: &[u8]) -> io::Result<()> {
+        let index = index_in_region(cc);
+        self.free(index);
+        let len = data.len() as u32;
+        let count = sectors_for(len);
+        let sector = self.allocate(count);
+        self.mark(sector, count, true);
+        self.file.seek(SeekFrom::Start(sector as u64 * SECTOR))?;
+        self.file.write_all(data)?;
+        self.set_entry(index, sector, len)
+    }
+    pub fn remove(&mut self, cc: Coords) -> io::Result<()> {
+        let index = index_in_region(cc);
+        self.free(index);
+        self.set_entry(index, 0, 0)
+    }
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    /* a region file nobody else uses, removed when dropped */
+    struct TempRegion(PathBuf);
+
+    impl TempRegion {
+        fn new(name: &str) -> TempRegion {
+            let path = std::env::temp_dir().join(format!("region-test-{}-{}.rf", process::id(), name));
+            let _ = fs::remove_file(&path);
+            TempRegion(path)
+        }
+        fn len(&self) -> u64 {
+            fs::metadata(&self.0).unwrap().len()
+        }
+    }
+
+    impl Drop for TempRegion {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn data(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    fn write_entry(path: &Path, cc: Coords, sector: u32, len: u32) {
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        let mut entry = [0; ENTRY];
+        NetworkEndian::write_u32(&mut entry[0..4], sector);
+        NetworkEndian::write_u32(&mut entry[4..8], len);
+        file.seek(SeekFrom::Start((index_in_region(cc) * ENTRY) as u64)).unwrap();
+        file.write_all(&entry).unwrap();
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let tmp = TempRegion::new("round-trip");
+        let mut region = Region::open(&tmp.0).unwrap();
+        let (a, b) = (Coords(0, 0, 0), Coords(15, 3, 7));
+        region.write(a, &data(100, 1)).unwrap();
+        region.write(b, &data(5000, 2)).unwrap();
+        assert_eq!(region.read(a).unwrap(), Some(data(100, 1)));
+        assert_eq!(region.read(b).unwrap(), Some(data(5000, 2)));
+        assert_eq!(region.read(Coords(1, 0, 0)).unwrap(), None);
+        assert_eq!(region.chunks(Coords(0, 0, 0)).collect::<Vec<_>>(), vec![a, b]);
+    }
+
+    #[test]
+    fn rewrite_with_larger_payload() {
+        let tmp = TempRegion::new("rewrite");
+        let mut region = Region::open(&tmp.0).unwrap();
+        let (a, b) = (Coords(0, 0, 0), Coords(1, 0, 0));
+        region.write(a, &data(100, 1)).unwrap();
+        region.write(b, &data(100, 2)).unwrap();
+        region.write(a, &data(3 * SECTOR as usize, 3)).unwrap();
+        assert_eq!(region.read(a).unwrap(), Some(data(3 * SECTOR as usize, 3)));
+        assert_eq!(region.read(b).unwrap(), Some(data(100, 2)));
+    }
+
+    #[test]
+    fn reuses_freed_sectors() {
+        let tmp = TempRegion::new("reuse");
+        let mut region = Region::open(&tmp.0).unwrap();
+        let (a, b, c) = (Coords(0, 0, 0), Coords(1, 0, 0), Coords(2, 0, 0));
+        region.write(a, &data(SECTOR as usize, 1)).unwrap();
+        region.write(b, &data(SECTOR as usize, 2)).unwrap();
+        let len = tmp.len();
+        region.remove(a).unwrap();
+        region.write(c, &data(100, 3)).unwrap();
+        assert_eq!(tmp.len(), len);
+        assert_eq!(region.read(a).unwrap(), None);
+        assert_eq!(region.read(b).unwrap(), Some(data(SECTOR as usize, 2)));
+        assert_eq!(region.read(c).unwrap(), Some(data(100, 3)));
+    }
+
+    #[test]
+    fn reopen_keeps_chunks() {
+        let tmp = TempRegion::new("reopen");
+        let (a, b) = (Coords(0, 0, 0), Coords(4, 5, 6));
+        {
+            let mut region = Region::open(&tmp.0).unwrap();
+            region.write(a, &data(100, 1)).unwrap();
+            region.write(b, &data(9000, 2)).unwrap();
+            region.remove(a).unwrap();
+            region.sync().unwrap();
+        }
+        let mut region = Region::open(&tmp.0).unwrap();
+        assert_eq!(region.read(a).unwrap(), None);
+        assert_eq!(region.read(b).unwrap(), Some(data(9000, 2)));
+        /* the sectors b takes aren't handed out again */
+        region.write(a, &data(100, 3)).unwrap();
+        assert_eq!(region.read(b).unwrap(), Some(data(9000, 2)));
+    }
+
+    #[test]
+    fn short_header_is_invalid() {
+        let tmp = TempRegion::new("short-header");
+        fs::write(&tmp.0, [0; 100]).unwrap();
+        assert_eq!(Region::open(&tmp.0).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn damaged_entries_are_not_read() {
+        let tmp = TempRegion::new("damaged");
+        let (a, b, c, d) = (Coords(0, 0, 0), Coords(1, 0, 0), Coords(2, 0, 0), Coords(3, 0, 0));
+        {
+            let mut region = Region::open(&tmp.0).unwrap();
+            region.write(a, &data(100, 1)).unwrap();
+        }
+        let (sector, _) = Region::open(&tmp.0).unwrap().table[index_in_region(a)];
+        /* into a's sectors, past the end of the file and into the header */
+        write_entry(&tmp.0, b, sector, 100);
+        write_entry(&tmp.0, c, sector + 10, 100);
+        write_entry(&tmp.0, d, 1, 100);
+        let mut region = Region::open(&tmp.0).unwrap();
+        assert_eq!(region.read(a).unwrap(), Some(data(100, 1)));
+        for cc in [b, c, d] {
+            assert_eq!(region.read(cc).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+        /* removing b mustn't free a's sectors */
+        region.remove(b).unwrap();
+        region.write(c, &data(100, 2)).unwrap();
+        assert_eq!(region.read(a).unwrap(), Some(data(100, 1)));
+        assert_eq!(region.read(b).unwrap(), None);
+        assert_eq!(region.read(c).unwrap(), Some(data(100, 2)));
+    }
+}
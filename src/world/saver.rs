@@ -0,0 +1,78 @@
+use super::chunk::Chunk;
+use super::coords::Coords;
+use super::storage::WorldStorage;
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+pub type SharedStorage = Arc<Mutex<Box<dyn WorldStorage + Send>>>;
+
+/// A copy of everything a save needs, taken at the moment it started.
+pub struct Snapshot {
+    pub chunks: Vec<(Coords, Chunk)>,
+    pub level: Vec<u8>,
+}
+
+/// Writes snapshots to the storage on its own thread, one at a time.
+pub struct Saver {
+    jobs: Option<Sender<Snapshot>>,
+    done: Receiver<io::Result<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+fn write_snapshot(storage: &SharedStorage, snapshot: Snapshot) -> io::Result<()> {
+    for (cc, chunk) in snapshot.chunks {
+        /* compress without holding the storage, so chunks can still be loaded */
+        let mut data = Vec::new();
+        chunk.store(&mut data)?;
+        storage.lock().unwrap().store_chunk(cc, &data)?;
+    }
+    let mut storage = storage.lock().unwrap();
+    storage.store_level(&snapshot.level)?;
+    storage.sync()
+}
+
+impl Saver {
+    pub fn new(storage: SharedStorage) -> Saver {
+        let (jobs, job_rx) = mpsc::channel::<Snapshot>();
+        let (done_tx, done) = mpsc::channel();
+        let thread = thread::Builder::new().name("saver".to_string()).spawn(move || {
+            for snapshot in job_rx {
+                if done_tx.send(write_snapshot(&storage, snapshot)).is_err() {
+                    break;
+                }
+            }
+        }).expect("could not start the saver thread");
+        Saver {
+            jobs: Some(jobs),
+            done,
+            thread: Some(thread),
+        }
+    }
+    pub fn start(&self, snapshot: Snapshot) -> io::Result<()> {
+        self.jobs.as_ref().unwrap().send(snapshot)
+            .map_err(|_| io::Error::other("the saver thread has stopped"))
+    }
+    /// Result of the save in progress, if it has finished.
+    pub fn try_finish(&self) -> Option<io::Result<()>> {
+        match self.done.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(io::Error::other("the saver thread has stopped"))),
+        }
+    }
+    /// Waits for the save in progress to finish.
+    pub fn finish(&self) -> io::Result<()> {
+        self.done.recv().unwrap_or_else(|_| Err(io::Error::other("the saver thread has stopped")))
+    }
+}
+
+impl Drop for Saver {
+    fn drop(&mut self) {
+        self.jobs.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use super::coords::Coords;
+use super::region::{self, Region};
+use crate::config::StorageKind;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Directory in the world where data that can't be read is moved to.
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+/// Where a world's chunks and level data are kept. Chunks are stored in
+/// their compressed form; level data is an opaque blob owned by `Map`.
+pub trait WorldStorage {
+    fn load_chunk(&mut self, cc: Coords) -> io::Result<Option<Vec<u8>>>;
+    fn store_chunk(&mut self, cc: Coords, data: &[u8]) -> io::Result<()>;
+    fn remove_chunk(&mut self, cc: Coords) -> io::Result<()>;
+    fn load_level(&mut self) -> io::Result<Option<Vec<u8>>>;
+    fn store_level(&mut self, data: &[u8]) -> io::Result<()>;
+    /// Every chunk that has been stored.
+    fn list_chunks(&mut self) -> io::Result<Vec<Coords>>;
+    /// Makes everything stored so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+pub fn open(kind: StorageKind, dir: &Path) -> io::Result<Box<dyn WorldStorage + Send>> {
+    Ok(match kind {
+        StorageKind::Region => Box::new(RegionStorage::open(dir)?),
+        StorageKind::Sqlite => Box::new(SqliteStorage::open(&dir.join("world.sqlite"))?),
+    })
+}
+
+/// Chunks in region files, level data in `level.toml`. Region files are never
+/// modified in place: the first write to a region copies it to a temporary
+/// file, which `sync` then puts in place of the original.
+pub struct RegionStorage {
+    dir: PathBuf,
+    regions: HashMap<Coords, Region>,
+    staged: HashSet<Coords>,
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Replaces the file at `path` so that it has either its old or its new
+/// contents even if the server dies halfway.
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = temp_path(path);
+    let mut f = File::create(&tmp)?;
+    f.write_all(data)?;
+    f.sync_all()?;
+    fs::rename(&tmp, path)?;
+    sync_dir(path.parent().unwrap_or_else(|| Path::new(".")))
+}
+
+fn parse_region_file_name(name: &str) -> Option<Coords> {
+    let mut fields = name.strip_prefix("region.")?.strip_suffix(".rf")?.split('.');
+    let c = Coords(fields.next()?.parse().ok()?, fields.next()?.parse().ok()?, fields.next()?.parse().ok()?);
+    match fields.next() {
+        None => Some(c),
+        Some(_) => None,
+    }
+}
+
+impl RegionStorage {
+    pub fn open(dir: &Path) -> io::Result<RegionStorage> {
+        /* left over from a save that didn't finish; the originals are intact */
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "tmp") {
+                println!("Removing unfinished save {}", path.display());
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(RegionStorage {
+            dir: dir.to_path_buf(),
+            regions: HashMap::new(),
+            staged: HashSet::new(),
+        })
+    }
+    fn region_path(&self, rc: Coords) -> PathBuf {
+        self.dir.join(format!("region.{}.{}.{}.rf", rc.0, rc.1, rc.2))
+    }
+    /* the region, switched over to its temporary copy */
+    fn staged_region(&mut self, rc: Coords) -> io::Result<&mut Region> {
+        if !self.staged.contains(&rc) {
+            let path = self.region_path(rc);
+            let tmp = temp_path(&path);
+            if path.exists() {
+                fs::copy(&path, &tmp)?;
+            }
+            self.regions.insert(rc, Region::open(&tmp)?);
+            self.staged.insert(rc);
+        }
+        Ok(self.regions.get_mut(&rc).unwrap())
+    }
+    /* the region; if it is damaged beyond reading, it is moved out of the
+     * way and replaced by an empty one */
+    fn region(&mut self, rc: Coords) -> io::Result<&mut Region> {
+        if !self.regions.contains_key(&rc) {
+            let path = self.region_path(rc);
+            let region = match Region::open(&path) {
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                    let quarantine = self.dir.join(QUARANTINE_DIR);
+                    let moved = quarantine.join(path.file_name().unwrap());
+                    println!("Region {:?} is damaged ({}), moving it to {}", rc, e, moved.display());
+                    fs::create_dir_all(&quarantine)?;
+                    fs::rename(&path, &moved)?;
+                    sync_dir(&self.dir)?;
+                    Region::open(&path)?
+                }
+                result => result?,
+            };
+            self.regions.insert(rc, region);
+        }
+        Ok(self.regions.get_mut(&rc).unwrap())
+    }
+}
+
+impl WorldStorage for RegionStorage {
+    fn load_chunk(&mut self, cc: Coords) -> io::Result<Option<Vec<u8>>> {
+        let rc = region::region_of(cc);
+        /* don't create region files just by looking at them */
+        if !self.regions.contains_key(&rc) && !self.region_path(rc).exists() {
+            return Ok(None);
+        }
+        self.region(rc)?.read(cc)
+    }
+    fn store_chunk(&mut self, cc: Coords, data: &[u8]) -> io::Result<()> {
+        self.staged_region(region::region_of(cc))?.write(cc, data)
+    }
+    fn remove_chunk(&mut self, cc: Coords) -> io::Result<()> {
+        let rc = region::region_of(cc);
+        if !self.regions.contains_key(&rc) && !self.region_path(rc).exists() {
+            return Ok(());
+        }
+        self.staged_region(rc)?.remove(cc)
+    }
+    fn load_level(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.dir.join("level.toml")) {
+            Ok(data) => Ok(Some(data)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    fn store_level(&mut self, data: &[u8]) -> io::Result<()> {
+        write_atomic(&self.dir.join("level.toml"), data)
+    }
+    fn list_chunks(&mut self) -> io::Result<Vec<Coords>> {
+        let mut chunks = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            if let Some(rc) = name.to_str().and_then(parse_region_file_name) {
+                chunks.extend(self.region(rc)?.chunks(rc));
+            }
+        }
+        Ok(chunks)
+    }
+    fn sync(&mut self) -> io::Result<()> {
+        if self.staged.is_empty() {
+            return Ok(());
+        }
+        let staged: Vec<Coords> = self.staged.drain().collect();
+        for rc in staged {
+            let path = self.region_path(rc);
+            self.regions[&rc].sync()?;
+            /* the open file now is the region itself */
+            fs::rename(temp_path(&path), &path)?;
+        }
+        sync_dir(&self.dir)
+    }
+}
+
+/// Everything in a single SQLite database. Writes are grouped in a
+/// transaction that `sync` commits.
+pub struct SqliteStorage {
+    db: Connection,
+}
+
+fn sql_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path) -> io::Result<SqliteStorage> {
+        let db = Connection::open(path).map_err(sql_error)?;
+        db.execute_batch("
+            CREATE TABLE IF NOT EXISTS chunk (
+                p INTEGER NOT NULL,
+                q INTEGER NOT NULL,
+                r INTEGER NOT NULL,
+                data BLOB NOT NULL,
+                PRIMARY KEY (p, q, r)
+            );
+            CREATE TABLE IF NOT EXISTS level (
+                id INTEGER PRIMARY KEY CHECK (id = 0),
+                data BLOB NOT NULL
+            );
+        ").map_err(sql_error)?;
+        Ok(SqliteStorage { db })
+    }
+    fn begin(&mut self) -> io::Result<()> {
+        if self.db.is_autocommit() {
+            self.db.execute_batch("BEGIN").map_err(sql_error)?;
+        }
+        Ok(())
+    }
+}
+
+impl WorldStorage for SqliteStorage {
+    fn load_chunk(&mut self, cc: Coords) -> io::Result<Option<Vec<u8>>> {
+        self.db.query_row("SELECT data FROM chunk WHERE p = ?1 AND q = ?2 AND r = ?3",
+            params![cc.0, cc.1, cc.2], |row| row.get(0)).optional().map_err(sql_error)
+    }
+    fn store_chunk(&mut self, cc: Coords, data: &[u8]) -> io::Result<()> {
+        self.begin()?;
+        self.db.execute("INSERT OR REPLACE INTO chunk (p, q, r, data) VALUES (?1, ?2, ?3, ?4)",
+            params![cc.0, cc.1, cc.2, data]).map_err(sql_error)?;
+        Ok(())
+    }
+    fn remove_chunk(&mut self, cc: Coords) -> io::Result<()> {
+        self.begin()?;
+        self.db.execute("DELETE FROM chunk WHERE p = ?1 AND q = ?2 AND r = ?3", params![cc.0, cc.1, cc.2])
+            .map_err(sql_error)?;
+        Ok(())
+    }
+    fn load_level(&mut self) -> io::Result<Option<Vec<u8>>> {
+        self.db.query_row("SELECT data FROM level WHERE id = 0", [], |row| row.get(0))
+            .optional().map_err(sql_error)
+    }
+    fn store_level(&mut self, data: &[u8]) -> io::Result<()> {
+        self.begin()?;
+        self.db.execute("INSERT OR REPLACE INTO level (id, data) VALUES (0, ?1)", params![data])
+            .map_err(sql_error)?;
+        Ok(())
+    }
+    fn list_chunks(&mut self) -> io::Result<Vec<Coords>> {
+        let mut stmt = self.db.prepare("SELECT p, q, r FROM chunk").map_err(sql_error)?;
+        let rows = stmt.query_map([], |row| Ok(Coords(row.get(0)?, row.get(1)?, row.get(2)?)))
+            .map_err(sql_error)?;
+        rows.collect::<Result<_, _>>().map_err(sql_error)
+    }
+    fn sync(&mut self) -> io::Result<()> {
+        if !self.db.is_autocommit() {
+            self.db.execute_batch("COMMIT").map_err(sql_error)?;
+        }
+        Ok(())
+    }
+}
@@ -1,12 +1,52 @@
 use noise::{NoiseFn, Seedable, BasicMulti, OpenSimplex};
 use super::block::Block;
+use super::chunk;
 use super::coords::Coords;
+use super::generator::WorldGenerator;
+use super::palette::Blocks;
 use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/* chunk columns whose terrain heights are kept for the chunks above and below */
+const CACHED_COLUMNS: usize = 256;
+
+/* terrain heights of a column of blocks, in fifths of a block */
+#[derive(Clone, Copy)]
+struct Column {
+    basalt: i64,
+    granite: i64,
+}
+
+impl Column {
+    fn height(&self) -> i64 {
+        cmp::max(Worldgen::SEA_LEVEL, cmp::max(self.basalt, self.granite)) / 5
+    }
+    fn block(&self, y: i64) -> Block {
+        if y * 5 < self.basalt {
+            Block::DARK_STONE
+        } else if y * 5 < self.granite {
+            Block::LIGHT_STONE
+        } else if y * 5 < Worldgen::SEA_LEVEL {
+            Block::WATER
+        } else {
+            Block::AIR
+        }
+    }
+}
+
+/* the columns of the chunk columns generated last, oldest first */
+#[derive(Default)]
+struct ColumnCache {
+    columns: HashMap<(i64, i64), Arc<Vec<Column>>>,
+    order: VecDeque<(i64, i64)>,
+}
 
 pub struct Worldgen {
     ocean: OpenSimplex,
     plains: OpenSimplex,
     mountain: BasicMulti,
+    cache: Mutex<ColumnCache>,
 }
 
 impl Worldgen {
@@ -17,32 +57,28 @@ impl Worldgen {
             ocean: OpenSimplex::new().set_seed(seed),
             plains: OpenSimplex::new().set_seed(seed),
             mountain: BasicMulti::new().set_seed(seed),
+            cache: Mutex::new(ColumnCache::default()),
         }
     }
 
-    pub fn air_chunk(&self, c: Coords) -> bool {
-        for x in 0..32 {
-            for z in 0..32 {
-                if self.height(c.0 + x, c.2 + z) >= c.1 {
-                    return false;
-                }
-            }
+    /* the 32×32 columns starting at x, z, indexed by x + z * 32 */
+    fn columns(&self, x: i64, z: i64) -> Arc<Vec<Column>> {
+        if let Some(columns) = self.cache.lock().unwrap().columns.get(&(x, z)) {
+            return Arc::clone(columns);
         }
-        true
-    }
-
-    pub fn whole_chunk(&self, c: Coords) -> [Block; 32*32*32] {
-        let mut bs = [Block::AIR; 32*32*32];
-        if !self.air_chunk(c) {
-            for x in 0..32 {
-                for y in 0..32 {
-                    for z in 0..32 {
-                        bs[x + y * 32 + z * 32 * 32] = self.default_block(c + Coords(x as i64, y as i64, z as i64));
-                    }
-                }
+        let columns: Arc<Vec<Column>> = Arc::new((0..32 * 32).map(|i| Column {
+            basalt: self.basalt((x + i % 32) * 5, (z + i / 32) * 5),
+            granite: self.granite((x + i % 32) * 5, (z + i / 32) * 5),
+        }).collect());
+        let mut cache = self.cache.lock().unwrap();
+        if cache.columns.insert((x, z), Arc::clone(&columns)).is_none() {
+            cache.order.push_back((x, z));
+            if cache.order.len() > CACHED_COLUMNS {
+                let oldest = cache.order.pop_front().unwrap();
+                cache.columns.remove(&oldest);
             }
         }
-        bs
+        columns
     }
 
     fn bidistort(&self, x: i64, z: i64, scale: f64) -> (i64, i64) {
@@ -77,21 +113,70 @@ impl Worldgen {
             0
         }
     }
+}
 
-    fn height(&self, x: i64, z: i64) -> i64 {
-        cmp::max(Worldgen::SEA_LEVEL, cmp::max(self.basalt(x * 5, z * 5), self.granite(x * 5, z * 5))) / 5
+/// The original terrain: seas, plains and mountains made from noise.
+impl WorldGenerator for Worldgen {
+    fn generate_chunk(&self, c: Coords) -> Blocks {
+        if self.is_air_chunk(c) {
+            return Blocks::Single(Block::AIR);
+        }
+        let columns = self.columns(c.0, c.2);
+        Blocks::from_fn(|i| {
+            let b = chunk::block_coords(i);
+            columns[(b.0 + b.2 * 32) as usize].block(c.1 + b.1)
+        })
     }
+    fn is_air_chunk(&self, c: Coords) -> bool {
+        self.columns(c.0, c.2).iter().all(|column| column.height() < c.1)
+    }
+    fn height_at(&self, x: i64, z: i64) -> i64 {
+        let columns = self.columns(x - x.rem_euclid(32), z - z.rem_euclid(32));
+        columns[(x.rem_euclid(32) + z.rem_euclid(32) * 32) as usize].height()
+    }
+}
 
-    fn default_block(&self, c: Coords) -> Block {
-        if c.1 * 5 < self.basalt(c.0 * 5, c.2 * 5) {
-            Block::DARK_STONE
-        } else if c.1 * 5 < self.granite(c.0 * 5, c.2 * 5) {
-            Block::LIGHT_STONE
-        } else if c.1 * 5 < Worldgen::SEA_LEVEL {
-            Block::WATER
-        } else {
-            Block::AIR
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* seed, chunk and CRC32 of its blocks, as generated before column
+     * heights were cached: land, mountains, sky, open sea down to its floor,
+     * and shores where the top of the chunk is partly water, partly land */
+    const GOLDEN: &[(u32, Coords, u32)] = &[
+        (0, Coords(0, 5, 0), 0xc8c96a7d),
+        (0, Coords(0, 6, 0), 0x46de529a),
+        (0, Coords(-3, 5, 7), 0xd7294240),
+        (0, Coords(10, 4, -10), 0x841a29bc),
+        (0, Coords(-174, 6, -13), 0x4405ac12),
+        (0, Coords(-174, 7, -13), 0xc50b1a76),
+        (0, Coords(-148, 7, 174), 0x66102869),
+        (0, Coords(-135, 6, -47), 0x7bef7aad),
+        (0, Coords(0, 100, 0), 0x011ffca6),
+        (0, Coords(-20, 4, 16), 0x657abde9),
+        (0, Coords(-20, 4, 17), 0x8f72b661),
+        (0, Coords(-3, 4, 7), 0xc6a19213),
+        (0, Coords(-19, 4, -7), 0x22e35c32),
+        (12345, Coords(0, 5, 0), 0x5bff6723),
+        (12345, Coords(0, 6, 0), 0xe21412e5),
+        (12345, Coords(-3, 5, 7), 0x9eacdc7f),
+        (12345, Coords(10, 4, -10), 0x6240bb75),
+        (12345, Coords(-174, 7, -13), 0x011ffca6),
+        (12345, Coords(-20, 4, -17), 0x6cd37fe9),
+        (12345, Coords(-19, 4, -6), 0xa326149c),
+    ];
+
+    #[test]
+    fn chunks_match_golden() {
+        for &(seed, cc, crc) in GOLDEN {
+            let worldgen = Worldgen::new(seed);
+            let c = Coords(cc.0 * 32, cc.1 * 32, cc.2 * 32);
+            /* twice, to check both fresh and cached column heights */
+            for _ in 0..2 {
+                let ids: Vec<u8> = worldgen.generate_chunk(c).iter().map(|b| b.matter).collect();
+                assert_eq!(crc32fast::hash(&ids), crc, "seed {} chunk {:?}", seed, cc);
+                assert_eq!(worldgen.is_air_chunk(c), ids.iter().all(|&id| id == Block::AIR.matter));
+            }
         }
     }
 }
-